@@ -0,0 +1,37 @@
+#ifndef H_BLUENOISE
+#define H_BLUENOISE
+
+#include "types.glsl"
+
+// Extracts either the first two or second two bytes from the uint
+// encoded byte buffer. As a result, byteIndex must be an even number
+// for this to work.
+vec2 TwoBnoiseSamples(const BluenoiseData bluenoise, const uint byteIndex) {
+  const uint uintIndex = byteIndex / 4;
+  const uint packedUInt = bluenoise.bluenoise[uintIndex];
+
+  // Calculate the shift amount based on the byte index modulo 4
+  const uint shiftAmount = (byteIndex % 4) * 8;
+
+  // Extract the two bytes at positions byteIndex and byteIndex+1 (next byte)
+  uvec2 res = uvec2((packedUInt >> shiftAmount), (packedUInt >> (shiftAmount + 8)));
+
+  return vec2((res.x & 0xFF) / 255.0, (res.y & 0xFF) / 255.0);
+}
+
+// Counts the traces over all frames, more than one per frame with motion blur.
+uint bnoiseFrame(const PushConstants p) {
+  return p.uniforms.tick * p.uniforms.time_samples + p.time_sample;
+}
+
+// Both values of one of the 64 slices of the 128x128 texture at the pixel, scrolled
+// by the offset and shifted by the shift of the frame.
+vec2 bnoiseSample(const PushConstants p, const uvec2 pixel, const uint slice) {
+  const uint x = (pixel.x + p.uniforms.bluenoise_offset_x) % 128;
+  const uint y = (pixel.y + p.uniforms.bluenoise_offset_y) % 128;
+  const uint byteIndex = 128 * 128 * 2 * slice + 128 * 2 * y + 2 * x;
+  const vec2 shift = vec2(p.uniforms.bluenoise_shift_x, p.uniforms.bluenoise_shift_y);
+  return fract(TwoBnoiseSamples(p.bluenoise, byteIndex) + shift);
+}
+
+#endif
@@ -0,0 +1,36 @@
+#ifndef H_PIXEL_ORDER
+#define H_PIXEL_ORDER
+
+// mirrors ray_render_plugin::PixelOrder
+const uint PIXEL_ORDER_LINEAR = 0;
+const uint PIXEL_ORDER_TILED = 1;
+const uint PIXEL_ORDER_MORTON = 2;
+
+const uint PIXEL_TILE_SIZE = 8;
+
+// Maps the launch id to the pixel it traces. Unless the order is linear the launch is
+// padded to whole tiles, which are walked one after the other, row by row or in
+// Z-order within the tile. This keeps consecutive invocations on neighbouring pixels.
+// The pixel can lie outside of the image.
+uvec2 launchPixel(const uvec2 launch_id, const uvec2 launch_size, const uint order) {
+  if (order == PIXEL_ORDER_LINEAR) { return launch_id; }
+
+  const uint index = launch_id.y * launch_size.x + launch_id.x;
+  const uint tile = index / (PIXEL_TILE_SIZE * PIXEL_TILE_SIZE);
+  const uint local = index % (PIXEL_TILE_SIZE * PIXEL_TILE_SIZE);
+  const uint tiles_x = launch_size.x / PIXEL_TILE_SIZE;
+  const uvec2 tile_origin = uvec2(tile % tiles_x, tile / tiles_x) * PIXEL_TILE_SIZE;
+
+  uvec2 offset = uvec2(local % PIXEL_TILE_SIZE, local / PIXEL_TILE_SIZE);
+  if (order == PIXEL_ORDER_MORTON) {
+    // the even bits are x, the odd bits are y
+    offset = uvec2(
+      (local & 1) | ((local >> 1) & 2) | ((local >> 2) & 4),
+      ((local >> 1) & 1) | ((local >> 2) & 2) | ((local >> 3) & 4)
+    );
+  }
+
+  return tile_origin + offset;
+}
+
+#endif
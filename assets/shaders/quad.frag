@@ -7,6 +7,10 @@ layout(location = 0) out vec4 out_Color;
 
 layout (set=0, binding=0) uniform sampler2D test;
 
+// mirrors ray_render_plugin::Tonemapper
+const uint TONEMAPPER_ACES = 0;
+const uint TONEMAPPER_HABLE = 1;
+
 layout(push_constant, std430) uniform Registers {
   UniformData uniforms;
 };
@@ -25,6 +29,17 @@ vec3 tonemapFilmic(const vec3 color) {
 	return (x * (6.2 * x + 0.5)) / (x * (6.2 * x + 1.7) + 0.06);
 }
 
+// John Hable's filmic curve, see http://filmicworlds.com/blog/filmic-tonemapping-operators/
+vec3 hableCurve(const vec3 x) {
+    float A = uniforms.shoulder_strength;
+    float B = uniforms.linear_strength;
+    float C = uniforms.linear_angle;
+    float D = uniforms.toe_strength;
+    float E = uniforms.toe_numerator;
+    float F = uniforms.toe_denominator;
+    return ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F;
+}
+
 vec3 applyVignette(vec3 color) {
     // Find the distance from the center of the screen
     vec2 uv = in_UV - 0.5;
@@ -45,10 +60,22 @@ vec3 applyVignette(vec3 color) {
 void main() {
   vec4 accBuffer = texture(test, in_UV);
   vec3 color = accBuffer.rgb / accBuffer.a;
-  color = pow(color, vec3(1.0/uniforms.gamma));
-  color = vec3(1.0) - exp(-color * uniforms.exposure);
 
-  color = acesFilm(color);
+  // debug views are false color already
+  if (uniforms.debug_view != DEBUG_VIEW_NONE) {
+    out_Color = vec4(color, 1.0);
+    return;
+  }
+
+  if (uniforms.tonemapper == TONEMAPPER_HABLE) {
+    color = hableCurve(color * uniforms.exposure) / hableCurve(vec3(uniforms.tonemap_whitepoint));
+    color = pow(max(color, vec3(0.0)), vec3(1.0/uniforms.gamma));
+  } else {
+    color = pow(color, vec3(1.0/uniforms.gamma));
+    color = vec3(1.0) - exp(-color * uniforms.exposure);
+    color = acesFilm(color);
+  }
+
   color = applyVignette(color);
 
   out_Color = vec4(color, 1.0);
@@ -3,6 +3,7 @@
 
 #extension GL_EXT_buffer_reference : enable
 #extension GL_EXT_scalar_block_layout : require
+#extension GL_EXT_shader_explicit_arithmetic_types_int64 : require
 
 struct Vertex {
   vec3 position;
@@ -42,12 +43,55 @@ layout (buffer_reference, scalar, buffer_reference_align = 8) readonly restrict
   float foginess;
   float fog_scatter;
   float sky_brightness;
+  uint tonemapper;
+  float tonemap_whitepoint;
+  float shoulder_strength;
+  float linear_strength;
+  float linear_angle;
+  float toe_strength;
+  float toe_numerator;
+  float toe_denominator;
+  uint primary_ray_flags;
+  uint gi_ray_flags;
+  uint bluenoise_offset_x;
+  uint bluenoise_offset_y;
+  float bluenoise_shift_x;
+  float bluenoise_shift_y;
+  uint bluenoise_decorrelated;
+  float focal_plane_tilt_x;
+  float focal_plane_tilt_y;
+  float near_blur;
+  float far_blur;
+  uint max_reflection_bounces;
+  uint max_refraction_bounces;
+  uint debug_view;
+  uint heatmap_max;
+  // traces per frame, more than 1 with motion blur
+  uint time_samples;
+  uint __motion_blur_padding;
+  mat4 shutter_open_inverse_view;
+  // equirectangular 360x180 degrees instead of inverse_projection
+  uint panorama;
+  // maximum luminance of the light gathered after a bounce (firefly_clamp) and
+  // seen directly (bokeh_clamp), 0 disables the clamp
+  float firefly_clamp;
+  float bokeh_clamp;
 };
 
+// mirrors ray_render_plugin::DebugView
+const uint DEBUG_VIEW_NONE = 0;
+const uint DEBUG_VIEW_TRAVERSAL_HEATMAP = 1;
+
 layout (buffer_reference, scalar, buffer_reference_align = 8) buffer restrict FocusData {
   float focal_distance;
 };
 
+// mirrors pipeline_statistics::RayCounters
+layout (buffer_reference, scalar, buffer_reference_align = 4) buffer RayCounters {
+  uint rays_traced;
+  uint any_hit_candidates;
+};
+
 layout (buffer_reference, scalar, buffer_reference_align = 16) readonly buffer VertexData {
   Vertex data[];
 };
@@ -84,6 +128,16 @@ layout (buffer_reference, scalar, buffer_reference_align = 16) readonly buffer M
   Material materials[];
 };
 
+struct InstanceData {
+  float opacity;
+  // the material of the first geometry, followed by one for each other geometry
+  uint material_offset;
+};
+
+layout (buffer_reference, scalar, buffer_reference_align = 4) readonly buffer InstanceDataBuffer {
+  InstanceData instances[];
+};
+
 layout (buffer_reference, scalar, buffer_reference_align = 8) readonly buffer BluenoiseData {
   uint bluenoise[];
 };
@@ -98,6 +152,8 @@ struct HitPayload {
   vec3 emission;
   vec4 surface_and_world_normal;
   vec3 absorption;
+  // candidate intersections along the ray, only counted by the traversal heatmap
+  uint heat;
 };
 
 struct PushConstants {
@@ -106,6 +162,19 @@ struct PushConstants {
   BluenoiseData bluenoise;
   FocusData focus;
   uint skydome;
+  // 0 when ray_counters is not bound
+  uint count_rays;
+  // indexed by gl_InstanceCustomIndexEXT
+  InstanceDataBuffer instance_data;
+  // 0 when the shutter opens, 1 when it closes (the current frame)
+  float time;
+  // index of this trace within the frame, only the first one may reset the accumulation
+  uint time_sample;
+  // see pixel_order.glsl
+  uint pixel_order;
+  RayCounters ray_counters;
+  // the TLAS of this time sample, see topLevelAS in raygen.rgen
+  uint64_t tlas;
 };
 
 void hitPayloadSetRoughness(inout HitPayload p, float r) {
@@ -0,0 +1,55 @@
+#version 460
+
+#include "types.glsl"
+
+layout(location = 0) in  vec2 in_UV;
+layout(location = 0) out vec4 out_Color;
+
+// the post processed image at render resolution
+layout (set=0, binding=0) uniform sampler2D source;
+
+layout(push_constant, std430) uniform Registers {
+  UniformData uniforms;
+};
+
+const float PI = 3.14159265359;
+
+float lanczos2(float x) {
+  x = abs(x);
+  if (x < 1e-5) { return 1.0; }
+  if (x >= 2.0) { return 0.0; }
+  const float px = PI * x;
+  return 2.0 * sin(px) * sin(px * 0.5) / (px * px);
+}
+
+// Separable Lanczos (a=2) over a 4x4 texel footprint.
+void main() {
+  const ivec2 size = textureSize(source, 0);
+  const vec2 pos = in_UV * vec2(size) - 0.5;
+  const ivec2 base = ivec2(floor(pos));
+  const vec2 f = pos - vec2(base);
+
+  vec3 color = vec3(0.0);
+  float weight_sum = 0.0;
+  vec3 min_color = vec3(1.0);
+  vec3 max_color = vec3(0.0);
+
+  for (int y = -1; y <= 2; y++) {
+    const float wy = lanczos2(float(y) - f.y);
+    for (int x = -1; x <= 2; x++) {
+      const float w = lanczos2(float(x) - f.x) * wy;
+      const vec3 c = texelFetch(source, clamp(base + ivec2(x, y), ivec2(0), size - 1), 0).rgb;
+      color += w * c;
+      weight_sum += w;
+
+      if (x >= 0 && x <= 1 && y >= 0 && y <= 1) {
+        min_color = min(min_color, c);
+        max_color = max(max_color, c);
+      }
+    }
+  }
+
+  // clamping to the nearest 2x2 texels removes the ringing of the negative lobes
+  color = clamp(color / weight_sum, min_color, max_color);
+  out_Color = vec4(color, 1.0);
+}
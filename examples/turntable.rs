@@ -0,0 +1,65 @@
+use bevy::prelude::*;
+use bevy_vulkan::{
+    dev_shaders::DevShaderPlugin,
+    dev_ui::DevUIPlugin,
+    gltf_mesh::{GltfModel, GltfModelHandle},
+    ray_default_plugins::RayDefaultPlugins,
+    ray_render_plugin::RenderConfig,
+    turntable::{Turntable, TurntableFinished, TurntablePlugin},
+};
+
+fn main() {
+    let mut app = App::new();
+    app.add_plugins(RayDefaultPlugins);
+    app.add_plugins(DevShaderPlugin);
+    app.add_plugins(DevUIPlugin);
+    app.add_plugins(TurntablePlugin);
+    app.add_systems(Startup, setup);
+    app.add_systems(Update, (start_when_loaded, report_finished));
+    app.run();
+}
+
+fn setup(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut render_config: ResMut<RenderConfig>,
+) {
+    render_config.sky_color = Vec4::splat(1.0);
+
+    commands.spawn((
+        Camera3d::default(),
+        Transform::from_xyz(0.0, 2.0, 8.0).looking_at(Vec3::new(0.0, 1.0, 0.0), Vec3::Y),
+    ));
+
+    commands.spawn((
+        GltfModelHandle(asset_server.load::<GltfModel>("models/sponza.glb")),
+        Transform::from_scale(Vec3::splat(0.012)),
+    ));
+}
+
+fn start_when_loaded(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    models: Query<&GltfModelHandle>,
+    mut started: Local<bool>,
+) {
+    if *started
+        || !models
+            .iter()
+            .all(|model| asset_server.is_loaded_with_dependencies(&model.0))
+    {
+        return;
+    }
+
+    *started = true;
+    commands.insert_resource(Turntable {
+        height: 1.0,
+        ..Turntable::new(Vec3::new(0.0, 1.5, 0.0), 6.0, 36, 64)
+    });
+}
+
+fn report_finished(mut finished: EventReader<TurntableFinished>) {
+    for _ in finished.read() {
+        log::info!("Turntable done, close the window to exit");
+    }
+}
@@ -1,4 +1,4 @@
-use std::sync::Mutex;
+use std::sync::{Mutex, OnceLock};
 
 use ash::vk;
 use bevy::{
@@ -19,6 +19,23 @@ use crate::{
     vulkan_asset::VulkanAsset,
 };
 
+/// Triangle packing runs on its own pool that leaves two cores free, so that a big
+/// model doesn't starve the main and render threads while it loads.
+fn packing_thread_pool() -> &'static rayon::ThreadPool {
+    static POOL: OnceLock<rayon::ThreadPool> = OnceLock::new();
+    POOL.get_or_init(|| {
+        let num_threads = std::thread::available_parallelism()
+            .map(|n| n.get().saturating_sub(2))
+            .unwrap_or(1)
+            .max(1);
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .thread_name(|i| format!("blas-packing-{}", i))
+            .build()
+            .unwrap()
+    })
+}
+
 #[derive(Debug, Clone, Copy, Default, Pod, Zeroable)]
 #[repr(C)]
 pub struct Vertex {
@@ -60,6 +77,18 @@ pub struct GeometryDescr {
     pub vertex_count: usize,
     pub first_index: usize,
     pub index_count: usize,
+    /// Skips the any-hit shader, must be off for alpha tested geometry.
+    pub opaque: bool,
+}
+
+impl GeometryDescr {
+    pub fn geometry_flags(&self) -> vk::GeometryFlagsKHR {
+        if self.opaque {
+            vk::GeometryFlagsKHR::OPAQUE
+        } else {
+            vk::GeometryFlagsKHR::empty()
+        }
+    }
 }
 
 #[derive(TypePath, Asset, Debug, Clone, Copy)]
@@ -80,6 +109,12 @@ pub struct RTXMaterial {
 }
 
 impl RTXMaterial {
+    /// Whether the geometry is shaded by the transmissive hit group, see
+    /// [`crate::raytracing_pipeline::RaytracingPipeline::transmissive_hit_shader`].
+    pub fn is_transmissive(&self) -> bool {
+        self.specular_transmission_factor > 0.0
+    }
+
     pub fn from_bevy_standard_material(material: &StandardMaterial) -> Self {
         RTXMaterial {
             base_color_factor: {
@@ -145,8 +180,52 @@ impl VulkanAsset for StandardMaterial {
     fn destroy_asset(_render_device: &RenderDevice, _prepared_asset: &Self::PreparedAsset) {}
 }
 
+/// How the acceleration structure of a mesh is built.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlasBuildOptions {
+    /// Allows refitting the acceleration structure after the vertices moved.
+    pub allow_update: bool,
+    /// Compacts the acceleration structure after building, this costs a few extra
+    /// submits but typically halves the memory usage.
+    pub compact: bool,
+}
+
+impl Default for BlasBuildOptions {
+    fn default() -> Self {
+        Self {
+            allow_update: false,
+            compact: true,
+        }
+    }
+}
+
+impl BlasBuildOptions {
+    /// For meshes whose vertices change, compacting them again on every change
+    /// isn't worth the extra submits.
+    pub fn dynamic() -> Self {
+        Self {
+            allow_update: true,
+            compact: false,
+        }
+    }
+
+    pub fn build_flags(&self) -> vk::BuildAccelerationStructureFlagsKHR {
+        let mut flags = vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE;
+        if self.allow_update {
+            flags |= vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE;
+        }
+        if self.compact {
+            flags |= vk::BuildAccelerationStructureFlagsKHR::ALLOW_COMPACTION;
+        }
+        flags
+    }
+}
+
 pub struct BLAS {
     pub acceleration_structure: AccelerationStructure,
+    pub build_options: BlasBuildOptions,
+    /// Whether all geometry is opaque, see [`GeometryDescr::opaque`].
+    pub opaque: bool,
     pub vertex_buffer: Buffer<Vertex>,
     pub triangle_buffer: Buffer<Triangle>,
     pub index_buffer: Buffer<u32>,
@@ -157,6 +236,11 @@ pub struct BLAS {
 }
 
 impl BLAS {
+    /// Every geometry has its own record in the SBT.
+    pub fn geometry_count(&self) -> u32 {
+        self.geometry_to_index.nr_elements as u32
+    }
+
     pub fn destroy(&self, render_device: &RenderDevice) {
         render_device
             .destroyer
@@ -211,6 +295,7 @@ pub fn build_blas_from_buffers(
     mut vertex_buffer_host: Buffer<Vertex>,
     mut index_buffer_host: Buffer<u32>,
     geometries: &[GeometryDescr],
+    options: BlasBuildOptions,
 ) -> BLAS {
     log::info!(
         "Building BLAS for mesh with {} vertices and {} indices and {} geometries",
@@ -250,62 +335,67 @@ pub fn build_blas_from_buffers(
 
     let triangle_buffer = Mutex::new(render_device.map_buffer(&mut triangle_buffer_host));
 
-    geometries
-        .into_par_iter()
-        .enumerate()
-        .for_each(|(geometry_idx, geometry)| {
-            let offset = geom_to_triangle[geometry_idx];
-            let mut buffer = vec![Triangle::default(); geometry.index_count / 3];
-            for tid in 0..(geometry.index_count / 3) {
-                let v0 = vertex_buffer[index_buffer[geometry.first_index + tid * 3 + 0] as usize];
-                let v1 = vertex_buffer[index_buffer[geometry.first_index + tid * 3 + 1] as usize];
-                let v2 = vertex_buffer[index_buffer[geometry.first_index + tid * 3 + 2] as usize];
-
-                let edge1 = v1.position - v0.position;
-                let edge2 = v2.position - v0.position;
-                let delta_uv1 = v1.uv - v0.uv;
-                let delta_uv2 = v2.uv - v0.uv;
-
-                let denom = delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x;
-                let tangent = if denom.abs() < 0.0001 {
-                    Vec3::Z
-                } else {
-                    let f = 1.0 / denom;
-                    Vec3::new(
-                        f * (delta_uv2.y * edge1.x - delta_uv1.y * edge2.x),
-                        f * (delta_uv2.y * edge1.y - delta_uv1.y * edge2.y),
-                        f * (delta_uv2.y * edge1.z - delta_uv1.y * edge2.z),
-                    )
-                    .normalize()
-                };
-
-                buffer[tid] = Triangle {
-                    tangent: Triangle::pack_normal(&tangent),
-                    padding: 0,
-                    normals: [
-                        Triangle::pack_normal(&v0.normal),
-                        Triangle::pack_normal(&v1.normal),
-                        Triangle::pack_normal(&v2.normal),
-                    ],
-                    uvs: [
-                        Triangle::pack_uv(&v0.uv),
-                        Triangle::pack_uv(&v1.uv),
-                        Triangle::pack_uv(&v2.uv),
-                    ],
-                };
-            }
-            log::info!(
-                "Packed geometry {}/{} with {} triangles",
-                geometry_idx,
-                geometries.len(),
-                geometry.index_count / 3
-            );
-
-            let mut triangle_buffer = triangle_buffer.lock().unwrap();
-            for (i, t) in buffer.iter().enumerate() {
-                triangle_buffer[offset as usize + i] = *t;
-            }
-        });
+    packing_thread_pool().install(|| {
+        geometries
+            .into_par_iter()
+            .enumerate()
+            .for_each(|(geometry_idx, geometry)| {
+                let offset = geom_to_triangle[geometry_idx];
+                let mut buffer = vec![Triangle::default(); geometry.index_count / 3];
+                for tid in 0..(geometry.index_count / 3) {
+                    let v0 =
+                        vertex_buffer[index_buffer[geometry.first_index + tid * 3 + 0] as usize];
+                    let v1 =
+                        vertex_buffer[index_buffer[geometry.first_index + tid * 3 + 1] as usize];
+                    let v2 =
+                        vertex_buffer[index_buffer[geometry.first_index + tid * 3 + 2] as usize];
+
+                    let edge1 = v1.position - v0.position;
+                    let edge2 = v2.position - v0.position;
+                    let delta_uv1 = v1.uv - v0.uv;
+                    let delta_uv2 = v2.uv - v0.uv;
+
+                    let denom = delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x;
+                    let tangent = if denom.abs() < 0.0001 {
+                        Vec3::Z
+                    } else {
+                        let f = 1.0 / denom;
+                        Vec3::new(
+                            f * (delta_uv2.y * edge1.x - delta_uv1.y * edge2.x),
+                            f * (delta_uv2.y * edge1.y - delta_uv1.y * edge2.y),
+                            f * (delta_uv2.y * edge1.z - delta_uv1.y * edge2.z),
+                        )
+                        .normalize()
+                    };
+
+                    buffer[tid] = Triangle {
+                        tangent: Triangle::pack_normal(&tangent),
+                        padding: 0,
+                        normals: [
+                            Triangle::pack_normal(&v0.normal),
+                            Triangle::pack_normal(&v1.normal),
+                            Triangle::pack_normal(&v2.normal),
+                        ],
+                        uvs: [
+                            Triangle::pack_uv(&v0.uv),
+                            Triangle::pack_uv(&v1.uv),
+                            Triangle::pack_uv(&v2.uv),
+                        ],
+                    };
+                }
+                log::info!(
+                    "Packed geometry {}/{} with {} triangles",
+                    geometry_idx,
+                    geometries.len(),
+                    geometry.index_count / 3
+                );
+
+                let mut triangle_buffer = triangle_buffer.lock().unwrap();
+                for (i, t) in buffer.iter().enumerate() {
+                    triangle_buffer[offset as usize + i] = *t;
+                }
+            });
+    });
 
     let vertex_buffer_device: Buffer<Vertex> = render_device.create_device_buffer(
         vertex_count as u64,
@@ -366,9 +456,9 @@ pub fn build_blas_from_buffers(
 
     let geometry_infos = geometries
         .iter()
-        .map(|_| {
+        .map(|geometry| {
             vk::AccelerationStructureGeometryKHR::default()
-                .flags(vk::GeometryFlagsKHR::OPAQUE)
+                .flags(geometry.geometry_flags())
                 .geometry_type(vk::GeometryTypeKHR::TRIANGLES)
                 .geometry(vk::AccelerationStructureGeometryDataKHR {
                     triangles: vk::AccelerationStructureGeometryTrianglesDataKHR::default()
@@ -389,10 +479,7 @@ pub fn build_blas_from_buffers(
 
     let combined_build_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
         .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
-        .flags(
-            vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE
-                | vk::BuildAccelerationStructureFlagsKHR::ALLOW_COMPACTION,
-        )
+        .flags(options.build_flags())
         .geometries(&geometry_infos);
 
     let primitive_counts = geometries
@@ -425,10 +512,7 @@ pub fn build_blas_from_buffers(
 
     let build_geometry_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
         .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
-        .flags(
-            vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE
-                | vk::BuildAccelerationStructureFlagsKHR::ALLOW_COMPACTION,
-        )
+        .flags(options.build_flags())
         .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
         .dst_acceleration_structure(acceleration_structure.handle)
         .geometries(&geometry_infos)
@@ -474,109 +558,113 @@ pub fn build_blas_from_buffers(
     };
 
     // compaction
-    let query_pool_info = vk::QueryPoolCreateInfo::default()
-        .query_type(vk::QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR)
-        .query_count(1);
+    if options.compact {
+        let query_pool_info = vk::QueryPoolCreateInfo::default()
+            .query_type(vk::QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR)
+            .query_count(1);
 
-    let query_pool = unsafe {
-        render_device
-            .device
-            .create_query_pool(&query_pool_info, None)
-    }
-    .unwrap();
-    unsafe {
-        render_device.run_transfer_commands(&|cmd_buffer| {
+        let query_pool = unsafe {
             render_device
                 .device
-                .cmd_reset_query_pool(cmd_buffer, query_pool, 0, 1);
-        })
-    }
+                .create_query_pool(&query_pool_info, None)
+        }
+        .unwrap();
+        unsafe {
+            render_device.run_transfer_commands(&|cmd_buffer| {
+                render_device
+                    .device
+                    .cmd_reset_query_pool(cmd_buffer, query_pool, 0, 1);
+            })
+        }
 
-    unsafe {
-        render_device.run_transfer_commands(&|cmd_buffer| {
+        unsafe {
+            render_device.run_transfer_commands(&|cmd_buffer| {
+                render_device
+                    .ext_acc_struct
+                    .cmd_write_acceleration_structures_properties(
+                        cmd_buffer,
+                        std::slice::from_ref(&acceleration_structure.handle),
+                        vk::QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR,
+                        query_pool,
+                        0,
+                    );
+            })
+        }
+
+        let mut compacted_sizes = [0];
+        unsafe {
             render_device
-                .ext_acc_struct
-                .cmd_write_acceleration_structures_properties(
-                    cmd_buffer,
-                    std::slice::from_ref(&acceleration_structure.handle),
-                    vk::QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR,
+                .device
+                .get_query_pool_results::<u64>(
                     query_pool,
                     0,
-                );
-        })
-    }
-
-    let mut compacted_sizes = [0];
-    unsafe {
-        render_device
-            .device
-            .get_query_pool_results::<u64>(
-                query_pool,
-                0,
-                &mut compacted_sizes,
-                vk::QueryResultFlags::WAIT,
-            )
-            .unwrap();
-    };
-
-    log::info!(
-        "BLAS compaction: {} -> {} ({}%)",
-        size_info.acceleration_structure_size,
-        compacted_sizes[0],
-        (compacted_sizes[0] as f32 / size_info.acceleration_structure_size as f32) * 100.0
-    );
+                    &mut compacted_sizes,
+                    vk::QueryResultFlags::WAIT,
+                )
+                .unwrap();
+        };
+
+        log::info!(
+            "BLAS compaction: {} -> {} ({}%)",
+            size_info.acceleration_structure_size,
+            compacted_sizes[0],
+            (compacted_sizes[0] as f32 / size_info.acceleration_structure_size as f32) * 100.0
+        );
 
-    let compacted_buffer = render_device.create_device_buffer::<u8>(
-        compacted_sizes[0],
-        vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR,
-    );
+        let compacted_buffer = render_device.create_device_buffer::<u8>(
+            compacted_sizes[0],
+            vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR,
+        );
 
-    let compacted_as_info = vk::AccelerationStructureCreateInfoKHR::default()
-        .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
-        .size(compacted_sizes[0])
-        .buffer(compacted_buffer.handle);
+        let compacted_as_info = vk::AccelerationStructureCreateInfoKHR::default()
+            .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
+            .size(compacted_sizes[0])
+            .buffer(compacted_buffer.handle);
 
-    let compacted_as = unsafe {
-        render_device
-            .ext_acc_struct
-            .create_acceleration_structure(&compacted_as_info, None)
-    }
-    .unwrap();
-
-    unsafe {
-        render_device.run_transfer_commands(&|cmd_buffer| {
-            let copy_info = vk::CopyAccelerationStructureInfoKHR::default()
-                .src(acceleration_structure.handle)
-                .dst(compacted_as)
-                .mode(vk::CopyAccelerationStructureModeKHR::COMPACT);
+        let compacted_as = unsafe {
             render_device
                 .ext_acc_struct
-                .cmd_copy_acceleration_structure(cmd_buffer, &copy_info);
-        })
-    }
+                .create_acceleration_structure(&compacted_as_info, None)
+        }
+        .unwrap();
+
+        unsafe {
+            render_device.run_transfer_commands(&|cmd_buffer| {
+                let copy_info = vk::CopyAccelerationStructureInfoKHR::default()
+                    .src(acceleration_structure.handle)
+                    .dst(compacted_as)
+                    .mode(vk::CopyAccelerationStructureModeKHR::COMPACT);
+                render_device
+                    .ext_acc_struct
+                    .cmd_copy_acceleration_structure(cmd_buffer, &copy_info);
+            })
+        }
 
-    unsafe {
-        render_device
-            .destroyer
-            .destroy_acceleration_structure(acceleration_structure.handle);
-        render_device
-            .destroyer
-            .destroy_buffer(acceleration_structure.buffer.handle);
-        render_device.device.destroy_query_pool(query_pool, None);
+        unsafe {
+            render_device
+                .destroyer
+                .destroy_acceleration_structure(acceleration_structure.handle);
+            render_device
+                .destroyer
+                .destroy_buffer(acceleration_structure.buffer.handle);
+            render_device.device.destroy_query_pool(query_pool, None);
+        }
+        acceleration_structure.buffer = compacted_buffer;
+        acceleration_structure.handle = compacted_as;
+        acceleration_structure.address = unsafe {
+            render_device
+                .ext_acc_struct
+                .get_acceleration_structure_device_address(
+                    &vk::AccelerationStructureDeviceAddressInfoKHR::default()
+                        .acceleration_structure(acceleration_structure.handle),
+                )
+        };
     }
-    acceleration_structure.buffer = compacted_buffer;
-    acceleration_structure.handle = compacted_as;
-    acceleration_structure.address = unsafe {
-        render_device
-            .ext_acc_struct
-            .get_acceleration_structure_device_address(
-                &vk::AccelerationStructureDeviceAddressInfoKHR::default()
-                    .acceleration_structure(acceleration_structure.handle),
-            )
-    };
 
     BLAS {
         acceleration_structure,
+        build_options: options,
+        opaque: geometries.iter().all(|geometry| geometry.opaque),
         vertex_buffer: vertex_buffer_device,
         triangle_buffer: triangle_buffer_device,
         index_buffer: index_buffer_device,
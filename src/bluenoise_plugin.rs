@@ -3,10 +3,10 @@ use ash::vk;
 use bevy::{prelude::*, render::RenderApp};
 
 use crate::{
-    ray_render_plugin::TeardownSchedule,
+    ray_render_plugin::RenderAppExt,
     render_buffer::{Buffer, BufferProvider},
     render_device::RenderDevice,
-    render_texture::padd_pixel_bytes_rgba_unorm,
+    render_texture::padd_pixel_bytes_rgba,
 };
 
 pub struct BlueNoisePlugin;
@@ -37,7 +37,7 @@ impl Plugin for BlueNoisePlugin {
             let data = &buf[..info.buffer_size()];
 
             let bytes_per_pixel = data.len() / (128 * 128);
-            let padded_data = padd_pixel_bytes_rgba_unorm(&data, bytes_per_pixel as u32, 128, 128);
+            let padded_data = padd_pixel_bytes_rgba(&data, bytes_per_pixel, &[0], &[255], 128, 128);
 
             for y in 0..128 {
                 for x in 0..128 {
@@ -65,7 +65,7 @@ impl Plugin for BlueNoisePlugin {
             .destroyer
             .destroy_buffer(bluenoise_buffer_host.handle);
         render_app.insert_resource(BlueNoiseBuffer(bluenoise_buffer_device));
-        render_app.add_systems(TeardownSchedule, cleanup);
+        app.add_render_teardown(cleanup);
     }
 }
 
@@ -16,17 +16,25 @@ impl Plugin for DevShaderPlugin {
             fragment_shader: asset_server.load("shaders/quad.frag"),
         };
 
+        let upscale_filter = PostProcessFilter {
+            vertex_shader: asset_server.load("shaders/quad.vert"),
+            fragment_shader: asset_server.load("shaders/upscale.frag"),
+        };
+
         let rtx_pipeline = RaytracingPipeline {
             raygen_shader: asset_server.load("shaders/raygen.rgen"),
             miss_shader: asset_server.load("shaders/miss.rmiss"),
             hit_shader: asset_server.load("shaders/closest_hit.rchit"),
             sphere_intersection_shader: asset_server.load("shaders/sphere_intersection.rint"),
             sphere_hit_shader: asset_server.load("shaders/sphere_hit.rchit"),
+            any_hit_shader: asset_server.load("shaders/any_hit.rahit"),
+            transmissive_hit_shader: None,
         };
 
         let render_config = RenderConfig {
             rtx_pipeline: asset_server.add(rtx_pipeline),
             postprocess_pipeline: asset_server.add(filter),
+            upscale_pipeline: asset_server.add(upscale_filter),
             skydome: Some(asset_server.load("textures/sky.hdr")),
             ..default()
         };
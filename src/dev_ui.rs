@@ -14,7 +14,16 @@ use egui::{emath, Context, PlatformOutput, RawInput, ViewportId};
 use egui_ash_renderer::{DynamicRendering, Options, Renderer};
 use winit::event_loop::EventLoop;
 
-use crate::{extract::Extract, ray_render_plugin::TeardownSchedule, render_device::RenderDevice};
+use crate::{
+    extract::Extract,
+    pipeline_statistics::{PassStatistics, RayCounters},
+    pixel_probe::ProbedPixel,
+    ray_render_plugin::{
+        DebugView, MotionBlur, PhysicalCamera, PixelOrder, RayFlags, RenderAppExt, RenderConfig,
+        Tonemapper,
+    },
+    render_device::RenderDevice,
+};
 
 pub struct DevUIWorldState {
     pub egui_winit: egui_winit::State,
@@ -25,12 +34,23 @@ pub struct DevUIState {
     pub hidden: bool,
     pub ticks: usize,
     pub fps: f32,
-    pub gamma: f32,
-    pub exposure: f32,
-    pub aperture: f32,
-    pub foginess: f32,
-    pub fog_scatter: f32,
-    pub sky_brightness: f32,
+    /// (ray counters, trace milliseconds, trace, post process) of the previous frame
+    pub pipeline_statistics: Option<(
+        RayCounters,
+        Option<f32>,
+        Option<PassStatistics>,
+        Option<PassStatistics>,
+    )>,
+    /// average trace milliseconds of each [`PixelOrder`], see [`PixelOrder::ALL`]
+    pub trace_time_per_pixel_order: [Option<f32>; 3],
+    /// assets still being prepared on a worker thread
+    pub pending_assets: usize,
+    /// The post processed image of the current frame, see [`DevUI::render_target_texture`].
+    pub render_target: Option<egui::load::SizedTexture>,
+    /// Whether the preview showed `render_target` last frame, only then it is rendered.
+    pub preview_open: bool,
+    /// See [`crate::pixel_probe::PixelProbe`].
+    pub probed_pixel: Option<ProbedPixel>,
 }
 
 impl Default for DevUIState {
@@ -39,12 +59,12 @@ impl Default for DevUIState {
             hidden: false,
             ticks: 0,
             fps: 0.0,
-            gamma: 2.4,
-            exposure: 1.0,
-            aperture: 0.008,
-            foginess: 0.001,
-            fog_scatter: 0.9,
-            sky_brightness: 1.0,
+            pipeline_statistics: None,
+            trace_time_per_pixel_order: [None; 3],
+            pending_assets: 0,
+            render_target: None,
+            preview_open: false,
+            probed_pixel: None,
         }
     }
 }
@@ -53,6 +73,43 @@ impl Default for DevUIState {
 pub struct DevUI {
     pub egui_ctx: Context,
     pub renderer: Renderer,
+    render_target_layout: vk::DescriptorSetLayout,
+    /// One per frame in flight, the set of the previous frame may still be in use.
+    render_target_sets: [vk::DescriptorSet; 2],
+    render_target_textures: [egui::TextureId; 2],
+}
+
+impl DevUI {
+    /// Returns a texture showing `view`, the post processed image of the frame in
+    /// `SHADER_READ_ONLY_OPTIMAL` layout, for use in `ui.image`. The image is recreated on
+    /// resize, so the descriptor is written every frame.
+    pub fn render_target_texture(
+        &self,
+        render_device: &RenderDevice,
+        frame_idx: usize,
+        view: vk::ImageView,
+        extent: vk::Extent2D,
+    ) -> egui::load::SizedTexture {
+        let image_info = vk::DescriptorImageInfo::default()
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .image_view(view)
+            .sampler(render_device.linear_sampler);
+
+        let write = vk::WriteDescriptorSet::default()
+            .dst_set(self.render_target_sets[frame_idx % 2])
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(std::slice::from_ref(&image_info));
+
+        unsafe {
+            render_device.update_descriptor_sets(std::slice::from_ref(&write), &[]);
+        }
+
+        egui::load::SizedTexture::new(
+            self.render_target_textures[frame_idx % 2],
+            [extent.width as f32, extent.height as f32],
+        )
+    }
 }
 
 #[derive(Resource, Clone, Default)]
@@ -65,10 +122,14 @@ pub struct DevUIWorldStateUpdate {
 // set by the rendering app, consumed by the main app.
 pub struct DevUIPlatformOutput {
     pub platform_output: Arc<Mutex<Option<PlatformOutput>>>,
+    // (before, after) of every edit made through the ui that the main app has not
+    // applied yet, see `RenderConfig::apply_edit`.
+    pub render_config: Arc<Mutex<Vec<(RenderConfig, RenderConfig)>>>,
 }
 
 impl DevUIState {
-    pub fn render(&mut self, ctx: &egui::Context) {
+    pub fn render(&mut self, ctx: &egui::Context, config: &mut RenderConfig) {
+        self.preview_open = false;
         if self.hidden {
             return;
         }
@@ -76,23 +137,277 @@ impl DevUIState {
         egui::Window::new("Dev UI").resizable(true).show(ctx, |ui| {
             ui.label(format!("tick: {}", self.ticks));
             ui.label(format!("fps: {:.2}", self.fps));
+            if self.pending_assets > 0 {
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.label(format!("loading {} assets", self.pending_assets));
+                });
+            }
+            if let Some((ray_counters, trace_time, trace, post_process)) = &self.pipeline_statistics
+            {
+                egui::CollapsingHeader::new("Pipeline statistics").show(ui, |ui| {
+                    ui.label("trace (every time sample and the motion blur TLAS builds):");
+                    match trace_time {
+                        Some(ms) => ui.label(format!("  gpu time: {:.3} ms", ms)),
+                        None => ui.label("  gpu time: -"),
+                    };
+                    // switch the pixel order in the resolution section to fill these in
+                    for (pixel_order, ms) in
+                        PixelOrder::ALL.iter().zip(&self.trace_time_per_pixel_order)
+                    {
+                        if let Some(ms) = ms {
+                            ui.label(format!("  average with {:?}: {:.3} ms", pixel_order, ms));
+                        }
+                    }
+                    // the driver has no ray tracing statistics, the shaders count these
+                    ui.checkbox(&mut config.count_rays, "count rays (slows down the trace)");
+                    if config.count_rays {
+                        ui.label(format!("  rays traced: {}", ray_counters.rays_traced));
+                        ui.label(format!(
+                            "  any hit candidates: {}",
+                            ray_counters.any_hit_candidates
+                        ));
+                    }
+                    Self::pass_statistics(ui, trace, post_process.is_some());
+                    ui.label("post process (the filters, upscale pass and dev ui):");
+                    Self::pass_statistics(ui, post_process, post_process.is_some());
+                });
+            }
+            egui::CollapsingHeader::new("Pixel probe").show(ui, |ui| match &self.probed_pixel {
+                None => {
+                    ui.label("alt + click a pixel");
+                }
+                Some(probed) => {
+                    let radiance = probed.radiance();
+                    ui.label(format!("pixel: {:?}", probed.pixel));
+                    ui.label(format!(
+                        "radiance: {:.4} {:.4} {:.4}",
+                        radiance.x, radiance.y, radiance.z
+                    ));
+                    ui.label(format!(
+                        "raw: {:.4} {:.4} {:.4} {:.0}",
+                        probed.value.x, probed.value.y, probed.value.z, probed.value.w
+                    ));
+                }
+            });
             egui::CollapsingHeader::new("Camera")
                 .open(Some(true))
                 .show(ui, |ui| {
-                    Self::slider(ui, "gamma", &mut self.gamma, 1.5..=3.0);
-                    Self::slider(ui, "exposure", &mut self.exposure, 0.0..=5.0);
-                    Self::slider(ui, "aperture", &mut self.aperture, 0.0..=0.02);
+                    ui.checkbox(&mut config.panorama, "panorama (2:1)");
+                    let mut physical = config.physical_camera.is_some();
+                    ui.checkbox(&mut physical, "physical camera");
+                    if physical != config.physical_camera.is_some() {
+                        config.physical_camera = physical.then(PhysicalCamera::default);
+                    }
+                    if let Some(camera) = &mut config.physical_camera {
+                        Self::log_slider(ui, "ISO", &mut camera.iso, 50.0..=12800.0);
+                        Self::log_slider(
+                            ui,
+                            "shutter speed (s)",
+                            &mut camera.shutter_speed,
+                            1.0 / 8000.0..=1.0,
+                        );
+                        Self::log_slider(ui, "f-stop", &mut camera.f_stop, 1.0..=22.0);
+                        Self::slider(
+                            ui,
+                            "focal length (m)",
+                            &mut camera.focal_length,
+                            0.012..=0.3,
+                        );
+                        ui.label(format!(
+                            "EV100: {:.2}, exposure: {:.3}",
+                            camera.ev100(),
+                            camera.exposure()
+                        ));
+                    } else {
+                        Self::slider(ui, "aperture", &mut config.aperture, 0.0..=0.02);
+                    }
+                    Self::slider(ui, "near blur", &mut config.near_blur, 0.0..=4.0);
+                    Self::slider(ui, "far blur", &mut config.far_blur, 0.0..=4.0);
+                    let mut motion_blur = config.motion_blur.is_some();
+                    ui.checkbox(&mut motion_blur, "motion blur");
+                    if motion_blur != config.motion_blur.is_some() {
+                        config.motion_blur = motion_blur.then(MotionBlur::default);
+                    }
+                    if let Some(motion_blur) = &mut config.motion_blur {
+                        Self::slider(ui, "shutter", &mut motion_blur.shutter_fraction, 0.0..=1.0);
+                        Self::slider(ui, "time samples", &mut motion_blur.samples, 2..=16);
+                    }
+                    Self::slider(ui, "tilt x", &mut config.focal_plane_tilt.x, -1.0..=1.0);
+                    Self::slider(ui, "tilt y", &mut config.focal_plane_tilt.y, -1.0..=1.0);
+                });
+            egui::CollapsingHeader::new("Resolution")
+                .open(Some(true))
+                .show(ui, |ui| {
+                    Self::slider(ui, "render scale", &mut config.render_scale, 0.25..=2.0);
+                    ui.checkbox(&mut config.upscale, "upscale");
+                    egui::ComboBox::from_label("pixel order")
+                        .selected_text(format!("{:?}", config.pixel_order))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(
+                                &mut config.pixel_order,
+                                PixelOrder::Linear,
+                                "Linear",
+                            );
+                            ui.selectable_value(
+                                &mut config.pixel_order,
+                                PixelOrder::Tiled,
+                                "Tiled",
+                            );
+                            ui.selectable_value(
+                                &mut config.pixel_order,
+                                PixelOrder::Morton,
+                                "Morton",
+                            );
+                        });
+                });
+            egui::CollapsingHeader::new("Tonemapping")
+                .open(Some(true))
+                .show(ui, |ui| {
+                    Self::slider(ui, "gamma", &mut config.gamma, 1.5..=3.0);
+                    if config.physical_camera.is_none() {
+                        Self::slider(ui, "exposure", &mut config.exposure, 0.0..=5.0);
+                    }
+                    egui::ComboBox::from_label("operator")
+                        .selected_text(format!("{:?}", config.tonemapper))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut config.tonemapper, Tonemapper::Aces, "Aces");
+                            ui.selectable_value(&mut config.tonemapper, Tonemapper::Hable, "Hable");
+                        });
+                    if config.tonemapper == Tonemapper::Hable {
+                        let curve = &mut config.filmic_curve;
+                        Self::slider(ui, "whitepoint", &mut config.tonemap_whitepoint, 1.0..=20.0);
+                        Self::slider(ui, "shoulder", &mut curve.shoulder_strength, 0.0..=1.0);
+                        Self::slider(ui, "linear", &mut curve.linear_strength, 0.0..=1.0);
+                        Self::slider(ui, "linear angle", &mut curve.linear_angle, 0.0..=1.0);
+                        Self::slider(ui, "toe", &mut curve.toe_strength, 0.0..=1.0);
+                        Self::slider(ui, "toe numerator", &mut curve.toe_numerator, 0.0..=0.1);
+                        Self::slider(
+                            ui,
+                            "toe denominator",
+                            &mut curve.toe_denominator,
+                            0.01..=1.0,
+                        );
+                    }
                 });
             egui::CollapsingHeader::new("Environment")
                 .open(Some(true))
                 .show(ui, |ui| {
-                    Self::slider(ui, "foginess", &mut self.foginess, 0.0..=0.2);
-                    Self::slider(ui, "fog scatter", &mut self.fog_scatter, -1.0..=1.0);
-                    Self::slider(ui, "sky_brightness", &mut self.sky_brightness, 0.0..=1.0);
+                    Self::slider(ui, "foginess", &mut config.foginess, 0.0..=0.2);
+                    Self::slider(ui, "fog scatter", &mut config.fog_scatter, -1.0..=1.0);
+                    Self::slider(ui, "sky_brightness", &mut config.sky_brightness, 0.0..=1.0);
                 });
+            egui::CollapsingHeader::new("Sampling").show(ui, |ui| {
+                ui.checkbox(&mut config.bluenoise_scroll, "scroll blue noise");
+                ui.checkbox(&mut config.bluenoise_rotation, "rotate blue noise");
+                Self::slider(
+                    ui,
+                    "reflection bounces",
+                    &mut config.max_reflection_bounces,
+                    0..=64,
+                );
+                Self::slider(
+                    ui,
+                    "refraction bounces",
+                    &mut config.max_refraction_bounces,
+                    0..=64,
+                );
+                let mut limited = config.max_samples.is_some();
+                ui.checkbox(&mut limited, "limit samples");
+                if limited != config.max_samples.is_some() {
+                    config.max_samples = limited.then_some(256);
+                }
+                if let Some(max_samples) = &mut config.max_samples {
+                    Self::slider(ui, "max frames", max_samples, 1..=4096);
+                }
+                for (label, clamp) in [
+                    ("firefly clamp", &mut config.firefly_clamp),
+                    ("bokeh clamp", &mut config.bokeh_clamp),
+                ] {
+                    let mut enabled = clamp.is_some();
+                    ui.checkbox(&mut enabled, label);
+                    if enabled != clamp.is_some() {
+                        *clamp = enabled.then_some(10.0);
+                    }
+                    if let Some(threshold) = clamp {
+                        Self::log_slider(ui, "max luminance", threshold, 0.1..=1000.0);
+                    }
+                }
+            });
+            let preview = egui::CollapsingHeader::new("Preview").show(ui, |ui| {
+                // rendered from the frame after opening
+                if let Some(render_target) = self.render_target {
+                    let width = ui.available_width();
+                    let size = render_target.size * (width / render_target.size.x);
+                    ui.image(egui::load::SizedTexture::new(render_target.id, size));
+                }
+            });
+            self.preview_open = preview.body_returned.is_some();
+            egui::CollapsingHeader::new("Debug view").show(ui, |ui| {
+                egui::ComboBox::from_label("view")
+                    .selected_text(format!("{:?}", config.debug_view))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut config.debug_view, DebugView::None, "None");
+                        ui.selectable_value(
+                            &mut config.debug_view,
+                            DebugView::TraversalHeatmap,
+                            "Traversal heatmap",
+                        );
+                    });
+                if config.debug_view == DebugView::TraversalHeatmap {
+                    Self::slider(ui, "heatmap max", &mut config.heatmap_max, 1..=256);
+                }
+            });
+            egui::CollapsingHeader::new("Ray flags").show(ui, |ui| {
+                Self::ray_flags(ui, &mut config.ray_flags);
+            });
         });
     }
 
+    fn ray_flags(ui: &mut egui::Ui, flags: &mut RayFlags) {
+        const FLAGS: [(&str, u32); 5] = [
+            ("opaque", RayFlags::OPAQUE),
+            ("terminate on first hit", RayFlags::TERMINATE_ON_FIRST_HIT),
+            ("skip closest hit", RayFlags::SKIP_CLOSEST_HIT_SHADER),
+            ("cull back faces", RayFlags::CULL_BACK_FACING_TRIANGLES),
+            ("cull front faces", RayFlags::CULL_FRONT_FACING_TRIANGLES),
+        ];
+
+        for (ray, mask) in [("primary", &mut flags.primary), ("gi", &mut flags.gi)] {
+            ui.label(ray);
+            ui.horizontal_wrapped(|ui| {
+                for (text, flag) in FLAGS {
+                    let mut enabled = *mask & flag != 0;
+                    if ui.checkbox(&mut enabled, text).changed() {
+                        *mask ^= flag;
+                    }
+                }
+            });
+        }
+    }
+
+    fn pass_statistics(ui: &mut egui::Ui, stats: &Option<PassStatistics>, supported: bool) {
+        match stats {
+            Some(stats) => {
+                ui.label(format!(
+                    "  vertex invocations: {}",
+                    stats.vertex_shader_invocations
+                ));
+                ui.label(format!("  primitives: {}", stats.clipping_primitives));
+                ui.label(format!(
+                    "  fragment invocations: {}",
+                    stats.fragment_shader_invocations
+                ));
+            }
+            None if supported => {
+                ui.label("  not recorded this frame");
+            }
+            None => {
+                ui.label("  not supported by the device");
+            }
+        }
+    }
+
     fn slider<Num: emath::Numeric>(
         ui: &mut egui::Ui,
         text: impl Into<egui::WidgetText>,
@@ -105,6 +420,20 @@ impl DevUIState {
                 .text_color(egui::Color32::LIGHT_BLUE),
         );
     }
+
+    fn log_slider(
+        ui: &mut egui::Ui,
+        text: impl Into<egui::WidgetText>,
+        value: &mut f32,
+        range: RangeInclusive<f32>,
+    ) {
+        ui.add(
+            egui::Slider::new(value, range)
+                .logarithmic(true)
+                .text(text)
+                .text_color(egui::Color32::LIGHT_BLUE),
+        );
+    }
 }
 
 pub struct DevUIPlugin;
@@ -136,7 +465,7 @@ impl Plugin for DevUIPlugin {
             state.unchecked_borrow_allocator()
         };
 
-        let renderer = Renderer::with_gpu_allocator(
+        let mut renderer = Renderer::with_gpu_allocator(
             allocator,
             render_device.device.clone(),
             DynamicRendering {
@@ -150,8 +479,12 @@ impl Plugin for DevUIPlugin {
         )
         .unwrap();
 
+        let (render_target_layout, render_target_sets) = create_texture_sets(render_device);
+        let render_target_textures = render_target_sets.map(|set| renderer.add_user_texture(set));
+
         let platform_output = DevUIPlatformOutput {
             platform_output: Arc::new(Mutex::new(None)),
+            render_config: Arc::new(Mutex::new(Vec::new())),
         };
 
         app.world_mut()
@@ -164,12 +497,16 @@ impl Plugin for DevUIPlugin {
         render_app
             .world_mut()
             .init_resource::<DevUIWorldStateUpdate>();
-        render_app
-            .world_mut()
-            .insert_resource(DevUI { egui_ctx, renderer });
+        render_app.world_mut().insert_resource(DevUI {
+            egui_ctx,
+            renderer,
+            render_target_layout,
+            render_target_sets,
+            render_target_textures,
+        });
         render_app.world_mut().insert_resource(platform_output);
         render_app.add_systems(ExtractSchedule, extract);
-        render_app.add_systems(TeardownSchedule, cleanup);
+        app.add_render_teardown(cleanup);
     }
 }
 
@@ -210,6 +547,7 @@ fn handle_output(
     windows: Query<Entity, With<PrimaryWindow>>,
     winit_windows: NonSend<WinitWindows>,
     platform_output: Res<DevUIPlatformOutput>,
+    render_config: Option<ResMut<RenderConfig>>,
 ) {
     if let Ok(window) = windows.get_single() {
         let window = winit_windows.get_window(window).unwrap();
@@ -219,8 +557,60 @@ fn handle_output(
                 .handle_platform_output(window, platform_output);
         }
     }
+
+    if let Some(mut render_config) = render_config {
+        // the ui edited a config that is at least a frame old, only apply the fields
+        // it changed so the ones set by the main app since then are kept.
+        for (before, after) in platform_output.render_config.lock().unwrap().drain(..) {
+            render_config.apply_edit(&before, &after);
+        }
+    }
+}
+
+/// Sets with the same layout as the ones the egui renderer creates for its textures.
+fn create_texture_sets(
+    render_device: &RenderDevice,
+) -> (vk::DescriptorSetLayout, [vk::DescriptorSet; 2]) {
+    let binding = vk::DescriptorSetLayoutBinding::default()
+        .binding(0)
+        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+        .descriptor_count(1)
+        .stage_flags(vk::ShaderStageFlags::FRAGMENT);
+
+    let layout_info =
+        vk::DescriptorSetLayoutCreateInfo::default().bindings(std::slice::from_ref(&binding));
+
+    let layout = unsafe {
+        render_device
+            .create_descriptor_set_layout(&layout_info, None)
+            .unwrap()
+    };
+
+    let sets = {
+        let descriptor_pool = render_device.descriptor_pool.lock().unwrap();
+        let layouts = [layout, layout];
+        let alloc_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(*descriptor_pool)
+            .set_layouts(&layouts);
+        unsafe {
+            render_device
+                .allocate_descriptor_sets(&alloc_info)
+                .unwrap()
+                .try_into()
+                .unwrap()
+        }
+    };
+
+    (layout, sets)
 }
 
 fn cleanup(world: &mut World) {
-    world.remove_resource::<DevUI>().unwrap();
+    let mut dev_ui = world.remove_resource::<DevUI>().unwrap();
+    for texture in dev_ui.render_target_textures {
+        dev_ui.renderer.remove_user_texture(texture);
+    }
+    let render_device = world.get_resource::<RenderDevice>().unwrap();
+    render_device
+        .destroyer
+        .destroy_descriptor_set_layout(dev_ui.render_target_layout);
 }
@@ -130,3 +130,23 @@ where
         (&self.item).into_iter()
     }
 }
+
+/// The main world entity a render world entity was extracted from. Render world
+/// entities are spawned anew every frame, this one stays the same.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MainEntity(pub Entity);
+
+/// Runs `systems` on a new render world the way the render app runs its extract
+/// schedule, `main_world` is moved in for the duration.
+#[cfg(test)]
+pub(crate) fn run_extract<M>(main_world: &mut World, systems: impl IntoSystemConfigs<M>) -> World {
+    let mut render_world = World::new();
+    render_world.insert_resource(MainWorld(std::mem::take(main_world)));
+
+    let mut schedule = Schedule::default();
+    schedule.add_systems(systems);
+    schedule.run(&mut render_world);
+
+    *main_world = render_world.remove_resource::<MainWorld>().unwrap().0;
+    render_world
+}
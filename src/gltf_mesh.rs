@@ -5,15 +5,21 @@ use bevy::{
     render::RenderApp,
     utils::{ConditionalSendFuture, HashMap},
 };
+use std::borrow::Cow;
 use thiserror::Error;
 
 use crate::{
-    blas::{build_blas_from_buffers, GeometryDescr, RTXMaterial, Vertex, BLAS},
-    extract::Extract,
+    blas::{build_blas_from_buffers, BlasBuildOptions, GeometryDescr, RTXMaterial, Vertex, BLAS},
+    extract::{Extract, MainEntity},
+    motion_blur::PreviousGlobalTransform,
     render_buffer::{Buffer, BufferProvider},
     render_device::RenderDevice,
     render_env::{DEFAULT_NORMAL_TEXTURE_IDX, WHITE_TEXTURE_IDX},
-    render_texture::{load_texture_from_bytes, padd_pixel_bytes_rgba_unorm, RenderTexture},
+    render_texture::{
+        load_texture_from_bytes, luminance_to_rgba, padd_pixel_bytes_rgba, source_row_stride,
+        strip_row_padding, RenderTexture,
+    },
+    tlas_builder::InstanceOpacity,
     vulkan_asset::{VulkanAsset, VulkanAssetExt},
 };
 
@@ -37,7 +43,10 @@ pub struct GltfModel {
     pub images: Vec<gltf::image::Data>,
 }
 
+/// Renders the meshes of the model's default scene. Requires `Transform` (and through it
+/// `GlobalTransform`), the extracted instance uses the propagated `GlobalTransform`.
 #[derive(Component, Deref, Clone)]
+#[require(Transform)]
 pub struct GltfModelHandle(pub Handle<GltfModel>);
 
 impl GltfModel {
@@ -158,6 +167,7 @@ impl VulkanAsset for GltfModel {
             vertex_buffer_host,
             index_buffer_host,
             &geometries,
+            BlasBuildOptions::default(),
         );
 
         blas.gltf_materials = Some(materials);
@@ -211,25 +221,35 @@ fn extract_mesh_data(
     let mut geometries = Vec::new();
     let mut vertex_buffer_head = 0;
     let mut index_buffer_head = 0;
-    let mut loaded_textures: HashMap<usize, RenderTexture> = HashMap::new();
+    // image index and padding to bindless index
+    let mut loaded_textures: HashMap<(usize, ChannelPadding), u32> = HashMap::new();
+
+    // `fallback` stands in for a missing texture, or one that does not fit in the
+    // bindless array anymore.
+    let mut load_cached_texture =
+        |texture: Option<gltf::Texture>, padding: ChannelPadding, fallback: u32| {
+            let Some(texture) = texture else {
+                return fallback;
+            };
+            let image_idx = texture.source().index();
+            if let Some(index) = loaded_textures.get(&(image_idx, padding)) {
+                return *index;
+            }
 
-    let mut load_cached_texture = |image_idx: usize| {
-        if let Some(res) = loaded_textures.get(&image_idx) {
-            return render_device.get_bindless_texture_index(&res).unwrap();
-        }
+            // skip the upload of a texture that would be replaced anyway
+            if !render_device.has_bindless_capacity() {
+                return fallback;
+            }
+            let Some(image) = load_gltf_texture(&render_device, gltf, image_idx, padding) else {
+                return fallback;
+            };
 
-        let Some(image) = load_gltf_texture(&render_device, gltf, image_idx) else {
-            return WHITE_TEXTURE_IDX;
+            let index = render_device.register_bindless_texture(&image, fallback);
+            textures.push(image);
+            loaded_textures.insert((image_idx, padding), index);
+            index
         };
 
-        render_device.register_bindless_texture(&image);
-        textures.push(image.clone());
-        loaded_textures.insert(image_idx, image);
-        return render_device
-            .get_bindless_texture_index(loaded_textures.get(&image_idx).unwrap())
-            .expect("Impossible");
-    };
-
     for primitive in mesh.primitives() {
         let positions = primitive
             .attributes()
@@ -248,6 +268,8 @@ fn extract_mesh_data(
             vertex_count: positions.count(),
             first_index: index_buffer_head,
             index_count: indices.count(),
+            // alpha masked and blended materials need the any-hit shader
+            opaque: primitive.material().alpha_mode() == gltf::material::AlphaMode::Opaque,
         };
 
         let mut emissive_factor = [0.0; 4];
@@ -260,41 +282,53 @@ fn extract_mesh_data(
             .transmission()
             .map_or(0.0, |t| t.transmission_factor());
 
-        let base_color_texture = primitive
-            .material()
-            .pbr_metallic_roughness()
-            .base_color_texture()
-            .map(|texture| load_cached_texture(texture.texture().source().index()))
-            .unwrap_or(WHITE_TEXTURE_IDX);
+        let base_color_texture = load_cached_texture(
+            primitive
+                .material()
+                .pbr_metallic_roughness()
+                .base_color_texture()
+                .map(|info| info.texture()),
+            ChannelPadding::Luminance,
+            WHITE_TEXTURE_IDX,
+        );
 
-        let base_emissive_texture = primitive
-            .material()
-            .emissive_texture()
-            .map(|texture| load_cached_texture(texture.texture().source().index()))
-            .unwrap_or(WHITE_TEXTURE_IDX);
+        let base_emissive_texture = load_cached_texture(
+            primitive
+                .material()
+                .emissive_texture()
+                .map(|info| info.texture()),
+            ChannelPadding::Luminance,
+            WHITE_TEXTURE_IDX,
+        );
 
-        let normal_texture = primitive
-            .material()
-            .normal_texture()
-            .map(|texture| load_cached_texture(texture.texture().source().index()))
-            .unwrap_or(DEFAULT_NORMAL_TEXTURE_IDX);
+        let normal_texture = load_cached_texture(
+            primitive
+                .material()
+                .normal_texture()
+                .map(|normal| normal.texture()),
+            ChannelPadding::Zero,
+            DEFAULT_NORMAL_TEXTURE_IDX,
+        );
 
-        let specular_transmission_texture =
+        let specular_transmission_texture = load_cached_texture(
             primitive
                 .material()
                 .transmission()
-                .map_or(WHITE_TEXTURE_IDX, |t| {
-                    t.transmission_texture()
-                        .map(|texture| load_cached_texture(texture.texture().source().index()))
-                        .unwrap_or(WHITE_TEXTURE_IDX)
-                });
+                .and_then(|t| t.transmission_texture())
+                .map(|info| info.texture()),
+            ChannelPadding::Zero,
+            WHITE_TEXTURE_IDX,
+        );
 
-        let metallic_roughness_texture = primitive
-            .material()
-            .pbr_metallic_roughness()
-            .metallic_roughness_texture()
-            .map(|texture| load_cached_texture(texture.texture().source().index()))
-            .unwrap_or(WHITE_TEXTURE_IDX);
+        let metallic_roughness_texture = load_cached_texture(
+            primitive
+                .material()
+                .pbr_metallic_roughness()
+                .metallic_roughness_texture()
+                .map(|info| info.texture()),
+            ChannelPadding::One,
+            WHITE_TEXTURE_IDX,
+        );
 
         let material = RTXMaterial {
             base_color_factor: primitive
@@ -381,42 +415,85 @@ fn extract_mesh_data(
     geometries
 }
 
+/// The value of the color channels an image is missing.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum ChannelPadding {
+    /// For data textures like normal maps, which have nothing in the missing channels.
+    Zero,
+    /// For the metallic roughness texture, which has metallic in blue.
+    One,
+    /// For color textures, a one or two channel image is grayscale (with alpha) so
+    /// the first channel is copied to the others.
+    Luminance,
+}
+
 fn load_gltf_texture(
     device: &RenderDevice,
     asset: &GltfModel,
     image_idx: usize,
+    padding: ChannelPadding,
 ) -> Option<RenderTexture> {
     let image = &asset.images[image_idx];
-    let (bytes, format) = match image.format {
-        gltf::image::Format::R8G8B8A8 => (image.pixels.clone(), vk::Format::R8G8B8A8_UNORM),
-        gltf::image::Format::R8G8B8 => (
-            padd_pixel_bytes_rgba_unorm(
-                &image.pixels,
-                3,
-                image.width as usize,
-                image.height as usize,
-            ),
-            vk::Format::R8G8B8A8_UNORM,
-        ),
-        gltf::image::Format::R8 => (
-            padd_pixel_bytes_rgba_unorm(
-                &image.pixels,
-                1,
-                image.width as usize,
-                image.height as usize,
-            ),
-            vk::Format::R8G8B8A8_UNORM,
-        ),
-        _ => {
-            log::warn!(
-                "WARNING: Unsupported texture format {:?}, ignoring...",
-                image.format
-            );
-            return None;
-        }
+
+    let pixel_size = match image.format {
+        gltf::image::Format::R8 => 1,
+        gltf::image::Format::R8G8 => 2,
+        gltf::image::Format::R8G8B8 => 3,
+        gltf::image::Format::R8G8B8A8 => 4,
+        gltf::image::Format::R16 => 2,
+        gltf::image::Format::R16G16 => 4,
+        gltf::image::Format::R16G16B16 => 6,
+        gltf::image::Format::R16G16B16A16 => 8,
+        gltf::image::Format::R32G32B32FLOAT => 12,
+        gltf::image::Format::R32G32B32A32FLOAT => 16,
+    };
+    // The upload takes padded rows as they are, the rows of images that are widened
+    // to rgba first lose their padding.
+    let row_stride = source_row_stride(image.pixels.len(), image.height, image.width * pixel_size);
+    let packed_pixels: Cow<[u8]> = match row_stride {
+        Some(row_stride) => Cow::Owned(strip_row_padding(
+            &image.pixels,
+            row_stride as usize,
+            (image.width * pixel_size) as usize,
+        )),
+        None => Cow::Borrowed(&image.pixels[..]),
     };
 
-    Some(load_texture_from_bytes(
+    let (width, height) = (image.width as usize, image.height as usize);
+    // (pixel data, row stride) of the upload
+    let as_is = || (Cow::Borrowed(&image.pixels[..]), row_stride);
+    let pad = |channels: usize, one: &[u8]| -> (Cow<[u8]>, Option<u32>) {
+        let color = match padding {
+            ChannelPadding::Luminance if channels <= 2 => {
+                let rgba = luminance_to_rgba(&packed_pixels, channels, one, width, height);
+                return (Cow::Owned(rgba), None);
+            }
+            ChannelPadding::Zero | ChannelPadding::Luminance => vec![0; one.len()],
+            ChannelPadding::One => one.to_vec(),
+        };
+        let rgba = padd_pixel_bytes_rgba(&packed_pixels, channels, &color, one, width, height);
+        (Cow::Owned(rgba), None)
+    };
+
+    // Everything is widened to rgba of the same channel type. Two channel normal maps
+    // are fine because the hit shader reconstructs z from xy.
+    let unorm8_one = &[u8::MAX];
+    let unorm16_one = &u16::MAX.to_ne_bytes();
+    let float_one = &1.0f32.to_ne_bytes();
+    let ((bytes, row_stride), format) = match image.format {
+        gltf::image::Format::R8 => (pad(1, unorm8_one), vk::Format::R8G8B8A8_UNORM),
+        gltf::image::Format::R8G8 => (pad(2, unorm8_one), vk::Format::R8G8B8A8_UNORM),
+        gltf::image::Format::R8G8B8 => (pad(3, unorm8_one), vk::Format::R8G8B8A8_UNORM),
+        gltf::image::Format::R8G8B8A8 => (as_is(), vk::Format::R8G8B8A8_UNORM),
+        gltf::image::Format::R16 => (pad(1, unorm16_one), vk::Format::R16G16B16A16_UNORM),
+        gltf::image::Format::R16G16 => (pad(2, unorm16_one), vk::Format::R16G16B16A16_UNORM),
+        gltf::image::Format::R16G16B16 => (pad(3, unorm16_one), vk::Format::R16G16B16A16_UNORM),
+        gltf::image::Format::R16G16B16A16 => (as_is(), vk::Format::R16G16B16A16_UNORM),
+        gltf::image::Format::R32G32B32FLOAT => (pad(3, float_one), vk::Format::R32G32B32A32_SFLOAT),
+        gltf::image::Format::R32G32B32A32FLOAT => (as_is(), vk::Format::R32G32B32A32_SFLOAT),
+    };
+
+    match load_texture_from_bytes(
         device,
         format,
         vk::ImageUsageFlags::SAMPLED,
@@ -424,14 +501,41 @@ fn load_gltf_texture(
         &bytes,
         image.width,
         image.height,
-    ))
+        row_stride,
+    ) {
+        Ok(texture) => Some(texture),
+        Err(e) => {
+            log::warn!(
+                "Texture {} could not be uploaded, ignoring: {}",
+                image_idx,
+                e
+            );
+            None
+        }
+    }
 }
 
-fn extract_gltfs(
+pub(crate) fn extract_gltfs(
     mut commands: Commands,
-    meshes: Extract<Query<(&GltfModelHandle, &Transform, &GlobalTransform)>>,
+    meshes: Extract<
+        Query<(
+            Entity,
+            &GltfModelHandle,
+            &Transform,
+            &GlobalTransform,
+            Option<&InstanceOpacity>,
+            Option<&PreviousGlobalTransform>,
+        )>,
+    >,
 ) {
-    for (mesh, t, gt) in meshes.iter() {
-        commands.spawn((mesh.clone(), t.clone(), gt.clone()));
+    for (e, mesh, t, gt, opacity, previous) in meshes.iter() {
+        commands.spawn((
+            MainEntity(e),
+            mesh.clone(),
+            t.clone(),
+            gt.clone(),
+            opacity.copied().unwrap_or_default(),
+            previous.copied().unwrap_or(PreviousGlobalTransform(*gt)),
+        ));
     }
 }
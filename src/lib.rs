@@ -6,7 +6,11 @@ pub mod dev_shaders;
 pub mod dev_ui;
 pub mod extract;
 pub mod gltf_mesh;
+pub mod motion_blur;
+pub mod pipeline_statistics;
+pub mod pixel_probe;
 pub mod post_process_filter;
+pub mod preset;
 pub mod ray_default_plugins;
 pub mod ray_render_plugin;
 pub mod raytracing_pipeline;
@@ -15,10 +19,12 @@ pub mod render_device;
 pub mod render_env;
 pub mod render_texture;
 pub mod sbt;
+pub mod screenshot;
 pub mod shader;
 pub mod sphere;
 pub mod swapchain;
 pub mod tlas_builder;
+pub mod turntable;
 pub mod vk_init;
 pub mod vk_utils;
 pub mod vulkan_asset;
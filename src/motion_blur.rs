@@ -0,0 +1,76 @@
+use bevy::{math::Affine3A, prelude::*, render::RenderApp};
+
+use crate::{
+    gltf_mesh::GltfModelHandle, ray_render_plugin::RenderAppExt, render_device::RenderDevice,
+    sphere::Sphere, swapchain::FRAMES_IN_FLIGHT, tlas_builder::TLAS,
+};
+
+/// The `GlobalTransform` of the previous frame, kept up to date for the camera and
+/// everything that is rendered. Used by [`crate::ray_render_plugin::MotionBlur`].
+#[derive(Component, Clone, Copy, Debug)]
+pub struct PreviousGlobalTransform(pub GlobalTransform);
+
+/// One TLAS per time sample for each frame in flight, rebuilt in the frame's command
+/// buffer. Only the instances and the acceleration structure are rebuilt, the materials
+/// and instance data are those of the main [`TLAS`]. A frame only rewrites its own
+/// TLASes once the previous frame using them has finished.
+#[derive(Resource, Default)]
+pub struct MotionBlurTLAS(pub [Vec<TLAS>; FRAMES_IN_FLIGHT]);
+
+/// Interpolates the scale, rotation and translation separately.
+pub fn lerp_affine(from: &Affine3A, to: &Affine3A, t: f32) -> Affine3A {
+    let (s0, r0, t0) = from.to_scale_rotation_translation();
+    let (s1, r1, t1) = to.to_scale_rotation_translation();
+    Affine3A::from_scale_rotation_translation(s0.lerp(s1, t), r0.slerp(r1, t), t0.lerp(t1, t))
+}
+
+pub struct MotionBlurPlugin;
+
+impl Plugin for MotionBlurPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(First, update_previous_transforms);
+
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app.init_resource::<MotionBlurTLAS>();
+        app.add_render_teardown(cleanup);
+    }
+}
+
+/// Runs before anything moves this frame, so the global transforms are still
+/// the ones of the previous frame.
+fn update_previous_transforms(
+    mut commands: Commands,
+    mut tracked: Query<(&GlobalTransform, &mut PreviousGlobalTransform)>,
+    untracked: Query<
+        (Entity, &GlobalTransform),
+        (
+            Without<PreviousGlobalTransform>,
+            Or<(
+                With<Camera3d>,
+                With<Mesh3d>,
+                With<GltfModelHandle>,
+                With<Sphere>,
+            )>,
+        ),
+    >,
+) {
+    for (transform, mut previous) in tracked.iter_mut() {
+        previous.0 = *transform;
+    }
+
+    for (entity, transform) in untracked.iter() {
+        commands
+            .entity(entity)
+            .insert(PreviousGlobalTransform(*transform));
+    }
+}
+
+fn cleanup(world: &mut World) {
+    let Some(motion_blur_tlas) = world.remove_resource::<MotionBlurTLAS>() else {
+        return;
+    };
+    let render_device = world.get_resource::<RenderDevice>().unwrap();
+    for tlas in motion_blur_tlas.0.iter().flatten() {
+        tlas.destroy(render_device);
+    }
+}
@@ -0,0 +1,302 @@
+use ash::vk;
+use bevy::prelude::*;
+
+use crate::{
+    ray_render_plugin::{PixelOrder, RenderAppExt},
+    render_buffer::{Buffer, BufferProvider},
+    render_device::RenderDevice,
+};
+
+/// The generic statistics of the graphics stages, Vulkan has none for the ray
+/// tracing stages so these count nothing of the trace dispatch itself.
+const STATISTIC_FLAGS: vk::QueryPipelineStatisticFlags = vk::QueryPipelineStatisticFlags::from_raw(
+    vk::QueryPipelineStatisticFlags::VERTEX_SHADER_INVOCATIONS.as_raw()
+        | vk::QueryPipelineStatisticFlags::CLIPPING_PRIMITIVES.as_raw()
+        | vk::QueryPipelineStatisticFlags::FRAGMENT_SHADER_INVOCATIONS.as_raw(),
+);
+
+/// Covers every trace of the frame and the motion blur TLAS builds.
+pub const TRACE_QUERY: u32 = 0;
+/// Also covers the upscale pass and the dev ui.
+pub const POST_PROCESS_QUERY: u32 = 1;
+const QUERY_COUNT: u32 = 2;
+
+/// Written right before and after the traces of the frame.
+pub const TRACE_BEGIN_TIMESTAMP: u32 = 0;
+pub const TRACE_END_TIMESTAMP: u32 = 1;
+const TIMESTAMP_COUNT: u32 = 2;
+
+/// The counters of a single pass, in the order of the bits in `STATISTIC_FLAGS`.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct PassStatistics {
+    pub vertex_shader_invocations: u64,
+    pub clipping_primitives: u64,
+    pub fragment_shader_invocations: u64,
+}
+
+/// Vulkan does not expose any ray tracing specific counters, so the shaders count
+/// these themselves (mirrors `RayCounters` in types.glsl). Summed over every trace
+/// of a frame, only counted with [`crate::ray_render_plugin::RenderConfig::count_rays`].
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct RayCounters {
+    pub rays_traced: u32,
+    /// Candidate intersections with geometry that is not opaque.
+    pub any_hit_candidates: u32,
+}
+
+#[derive(Resource)]
+pub struct PipelineStatistics {
+    /// Only present when the device supports pipeline statistics queries.
+    query_pool: Option<vk::QueryPool>,
+    recorded: bool,
+    ray_counters_buffer: Buffer<RayCounters>,
+    /// Only present when the queue supports timestamps.
+    timestamp_pool: Option<vk::QueryPool>,
+    /// Nanoseconds per timestamp tick.
+    timestamp_period: f32,
+    /// Masks out the bits of a timestamp that are not valid.
+    timestamp_mask: u64,
+    pub ray_counters: RayCounters,
+    /// `None` on frames without a trace.
+    pub trace: Option<PassStatistics>,
+    pub post_process: Option<PassStatistics>,
+    /// GPU time of the trace in milliseconds, `None` on frames without one. With
+    /// motion blur this covers every time sample and their TLAS builds.
+    pub trace_time: Option<f32>,
+    /// The [`PixelOrder`] of the trace that was recorded, set by the render loop.
+    pub traced_pixel_order: Option<PixelOrder>,
+    /// Running average of `trace_time` for each [`PixelOrder`] (in the order of
+    /// [`PixelOrder::ALL`]), so they can be compared on the same view.
+    pub trace_time_per_pixel_order: [Option<f32>; 3],
+}
+
+impl PipelineStatistics {
+    /// Reads back the results of the previous frame and resets the ray counters,
+    /// must be called after waiting for its fence.
+    pub unsafe fn read_back(&mut self, render_device: &RenderDevice) {
+        if !self.recorded {
+            return;
+        }
+
+        {
+            let mut counters = render_device.map_buffer(&mut self.ray_counters_buffer);
+            self.ray_counters = counters.as_slice_mut()[0];
+            counters.copy_from_slice(&[RayCounters::default()]);
+        }
+
+        if let Some(timestamp_pool) = self.timestamp_pool {
+            let mut timestamps = [0u64; TIMESTAMP_COUNT as usize];
+            self.trace_time = match render_device.get_query_pool_results(
+                timestamp_pool,
+                0,
+                &mut timestamps,
+                vk::QueryResultFlags::TYPE_64,
+            ) {
+                Ok(()) => {
+                    let ticks = timestamps[TRACE_END_TIMESTAMP as usize]
+                        .wrapping_sub(timestamps[TRACE_BEGIN_TIMESTAMP as usize])
+                        & self.timestamp_mask;
+                    let ms = ticks as f32 * self.timestamp_period / 1_000_000.0;
+                    if let Some(pixel_order) = self.traced_pixel_order {
+                        let average = &mut self.trace_time_per_pixel_order[pixel_order as usize];
+                        *average = Some(average.map_or(ms, |average| 0.95 * average + 0.05 * ms));
+                    }
+                    Some(ms)
+                }
+                // nothing was traced
+                Err(vk::Result::NOT_READY) => None,
+                Err(e) => {
+                    log::warn!("Failed to read trace timestamps: {:?}", e);
+                    None
+                }
+            };
+        }
+        self.traced_pixel_order = None;
+
+        let Some(query_pool) = self.query_pool else {
+            return;
+        };
+        // read one by one, the trace query is not recorded on every frame
+        let read = |query: u32| {
+            let mut result = [PassStatistics::default()];
+            match render_device.get_query_pool_results(
+                query_pool,
+                query,
+                &mut result,
+                vk::QueryResultFlags::TYPE_64,
+            ) {
+                Ok(()) => Some(result[0]),
+                Err(vk::Result::NOT_READY) => None,
+                Err(e) => {
+                    log::warn!("Failed to read pipeline statistics: {:?}", e);
+                    None
+                }
+            }
+        };
+        self.trace = read(TRACE_QUERY);
+        self.post_process = read(POST_PROCESS_QUERY);
+    }
+
+    /// The address of the [`RayCounters`] the shaders add to.
+    pub fn ray_counters_address(&self) -> u64 {
+        self.ray_counters_buffer.address
+    }
+
+    pub unsafe fn cmd_reset(
+        &mut self,
+        render_device: &RenderDevice,
+        cmd_buffer: vk::CommandBuffer,
+    ) {
+        if let Some(query_pool) = self.query_pool {
+            render_device.cmd_reset_query_pool(cmd_buffer, query_pool, 0, QUERY_COUNT);
+        }
+        if let Some(timestamp_pool) = self.timestamp_pool {
+            render_device.cmd_reset_query_pool(cmd_buffer, timestamp_pool, 0, TIMESTAMP_COUNT);
+        }
+        self.recorded = true;
+    }
+
+    /// Written once all previous commands are done, so the difference between two
+    /// timestamps is the time the commands in between took.
+    pub unsafe fn cmd_write_timestamp(
+        &self,
+        render_device: &RenderDevice,
+        cmd_buffer: vk::CommandBuffer,
+        timestamp: u32,
+    ) {
+        if let Some(timestamp_pool) = self.timestamp_pool {
+            render_device.cmd_write_timestamp(
+                cmd_buffer,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                timestamp_pool,
+                timestamp,
+            );
+        }
+    }
+
+    pub unsafe fn cmd_begin(
+        &self,
+        render_device: &RenderDevice,
+        cmd_buffer: vk::CommandBuffer,
+        query: u32,
+    ) {
+        if let Some(query_pool) = self.query_pool {
+            render_device.cmd_begin_query(
+                cmd_buffer,
+                query_pool,
+                query,
+                vk::QueryControlFlags::empty(),
+            );
+        }
+    }
+
+    pub unsafe fn cmd_end(
+        &self,
+        render_device: &RenderDevice,
+        cmd_buffer: vk::CommandBuffer,
+        query: u32,
+    ) {
+        if let Some(query_pool) = self.query_pool {
+            render_device.cmd_end_query(cmd_buffer, query_pool, query);
+        }
+    }
+}
+
+pub struct PipelineStatisticsPlugin;
+
+impl Plugin for PipelineStatisticsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_render_startup(setup);
+        app.add_render_teardown(cleanup);
+    }
+}
+
+fn setup(mut commands: Commands, render_device: Res<RenderDevice>) {
+    let query_pool = if render_device.supports_pipeline_statistics {
+        let query_pool_info = vk::QueryPoolCreateInfo::default()
+            .query_type(vk::QueryType::PIPELINE_STATISTICS)
+            .query_count(QUERY_COUNT)
+            .pipeline_statistics(STATISTIC_FLAGS);
+
+        Some(unsafe {
+            render_device
+                .create_query_pool(&query_pool_info, None)
+                .unwrap()
+        })
+    } else {
+        log::warn!("Pipeline statistics queries are not supported on this device");
+        None
+    };
+
+    let timestamp_valid_bits = unsafe {
+        render_device
+            .instance
+            .get_physical_device_queue_family_properties(render_device.physical_device)
+            [render_device.queue_family_idx as usize]
+            .timestamp_valid_bits
+    };
+    let timestamp_period = unsafe {
+        render_device
+            .instance
+            .get_physical_device_properties(render_device.physical_device)
+            .limits
+            .timestamp_period
+    };
+    let timestamp_pool = if timestamp_valid_bits > 0 && timestamp_period > 0.0 {
+        let query_pool_info = vk::QueryPoolCreateInfo::default()
+            .query_type(vk::QueryType::TIMESTAMP)
+            .query_count(TIMESTAMP_COUNT);
+
+        Some(unsafe {
+            render_device
+                .create_query_pool(&query_pool_info, None)
+                .unwrap()
+        })
+    } else {
+        log::warn!("Timestamp queries are not supported on this device");
+        None
+    };
+
+    let mut ray_counters_buffer: Buffer<RayCounters> =
+        render_device.create_host_buffer(1, vk::BufferUsageFlags::STORAGE_BUFFER);
+    render_device
+        .map_buffer(&mut ray_counters_buffer)
+        .copy_from_slice(&[RayCounters::default()]);
+
+    commands.insert_resource(PipelineStatistics {
+        query_pool,
+        recorded: false,
+        ray_counters_buffer,
+        timestamp_pool,
+        timestamp_period,
+        timestamp_mask: u64::MAX
+            .checked_shr(64 - timestamp_valid_bits.min(64))
+            .unwrap_or(0),
+        ray_counters: RayCounters::default(),
+        trace: None,
+        post_process: None,
+        trace_time: None,
+        traced_pixel_order: None,
+        trace_time_per_pixel_order: [None; 3],
+    });
+}
+
+fn cleanup(world: &mut World) {
+    let Some(statistics) = world.remove_resource::<PipelineStatistics>() else {
+        return;
+    };
+    let device = world.get_resource::<RenderDevice>().unwrap();
+    for query_pool in [statistics.query_pool, statistics.timestamp_pool]
+        .into_iter()
+        .flatten()
+    {
+        unsafe {
+            device.destroy_query_pool(query_pool, None);
+        }
+    }
+    device
+        .destroyer
+        .destroy_buffer(statistics.ray_counters_buffer.handle);
+}
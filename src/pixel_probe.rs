@@ -0,0 +1,108 @@
+use ash::vk;
+use bevy::prelude::*;
+use gpu_allocator::MemoryLocation;
+
+use crate::{
+    ray_render_plugin::RenderAppExt,
+    render_buffer::{Buffer, BufferProvider},
+    render_device::RenderDevice,
+    vk_init, vk_utils,
+};
+
+/// A pixel of the render target, before post processing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProbedPixel {
+    /// In render target pixels, which differ from the window with a render scale.
+    pub pixel: (u32, u32),
+    /// The sum of the accumulated frames, alpha counts them.
+    pub value: Vec4,
+}
+
+impl ProbedPixel {
+    /// The linear radiance averaged over the accumulated frames.
+    pub fn radiance(&self) -> Vec3 {
+        self.value.truncate() / self.value.w.max(1.0)
+    }
+}
+
+/// Reads back the pixel at [`crate::ray_render_plugin::RenderConfig::probe_pixel`]
+/// (alt + click) every frame, for debugging the shaders.
+#[derive(Resource)]
+pub struct PixelProbe {
+    buffer: Buffer<Vec4>,
+    /// The pixel copied by the previous frame.
+    recorded: Option<(u32, u32)>,
+    pub result: Option<ProbedPixel>,
+}
+
+impl PixelProbe {
+    /// Reads back the pixel copied by the previous frame, must be called after
+    /// waiting for its fence.
+    pub fn read_back(&mut self, render_device: &RenderDevice) {
+        let Some(pixel) = self.recorded.take() else {
+            return;
+        };
+
+        let value = render_device.map_buffer(&mut self.buffer).as_slice_mut()[0];
+        if self.result.map_or(true, |result| result.pixel != pixel) {
+            log::info!("Probed pixel {:?}: {}", pixel, value);
+        }
+        self.result = Some(ProbedPixel { pixel, value });
+    }
+
+    /// Copies `pixel` of the render target (in `GENERAL`) once the trace has written it.
+    pub unsafe fn cmd_copy(
+        &mut self,
+        render_device: &RenderDevice,
+        cmd_buffer: vk::CommandBuffer,
+        image: vk::Image,
+        pixel: (u32, u32),
+    ) {
+        vk_utils::trace_to_copy_barrier(render_device, cmd_buffer, image);
+
+        let region = vk_init::buffer_image_copy(1, 1, 0).image_offset(vk::Offset3D {
+            x: pixel.0 as i32,
+            y: pixel.1 as i32,
+            z: 0,
+        });
+        render_device.cmd_copy_image_to_buffer(
+            cmd_buffer,
+            image,
+            vk::ImageLayout::GENERAL,
+            self.buffer.handle,
+            std::slice::from_ref(&region),
+        );
+        self.recorded = Some(pixel);
+    }
+}
+
+pub struct PixelProbePlugin;
+
+impl Plugin for PixelProbePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_render_startup(setup);
+        app.add_render_teardown(cleanup);
+    }
+}
+
+fn setup(mut commands: Commands, render_device: Res<RenderDevice>) {
+    let buffer = render_device.create_buffer(
+        1,
+        vk::BufferUsageFlags::TRANSFER_DST,
+        MemoryLocation::GpuToCpu,
+    );
+
+    commands.insert_resource(PixelProbe {
+        buffer,
+        recorded: None,
+        result: None,
+    });
+}
+
+fn cleanup(world: &mut World) {
+    let Some(probe) = world.remove_resource::<PixelProbe>() else {
+        return;
+    };
+    let render_device = world.get_resource::<RenderDevice>().unwrap();
+    render_device.destroyer.destroy_buffer(probe.buffer.handle);
+}
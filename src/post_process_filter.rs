@@ -18,7 +18,9 @@ pub struct CompiledPostProcessFilter {
     pub pipeline: vk::Pipeline,
     pub pipeline_layout: vk::PipelineLayout,
     pub descriptor_set_layout: vk::DescriptorSetLayout,
-    pub descriptor_sets: [vk::DescriptorSet; 2],
+    /// Two per frame in flight, the filter can be drawn at the render resolution (for
+    /// the upscaler or the dev ui preview) and to the swapchain in the same frame.
+    pub descriptor_sets: [vk::DescriptorSet; 4],
 }
 
 impl VulkanAsset for PostProcessFilter {
@@ -90,7 +92,7 @@ impl VulkanAsset for PostProcessFilter {
 
         let descriptor_sets = {
             let descriptor_pool = render_device.descriptor_pool.lock().unwrap();
-            let layouts = [descriptor_set_layout; 2];
+            let layouts = [descriptor_set_layout; 4];
             let alloc_info = vk::DescriptorSetAllocateInfo::default()
                 .descriptor_pool(*descriptor_pool)
                 .set_layouts(&layouts);
@@ -0,0 +1,256 @@
+use std::path::Path;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    debug_camera::DebugCamera,
+    ray_render_plugin::{FilmicCurve, MotionBlur, RenderConfig},
+};
+
+#[non_exhaustive]
+#[derive(Debug, Error)]
+pub enum PresetError {
+    #[error("Could not access preset: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Could not serialize preset: {0}")]
+    Serialize(#[from] ron::Error),
+    #[error("Could not parse preset: {0}")]
+    Parse(#[from] ron::error::SpannedError),
+}
+
+/// Position, orientation and vertical field of view (in radians) of the camera.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CameraPose {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub fov: f32,
+}
+
+impl CameraPose {
+    pub fn from_camera(transform: &Transform, projection: &Projection) -> Self {
+        let fov = match projection {
+            Projection::Perspective(perspective) => perspective.fov,
+            _ => PerspectiveProjection::default().fov,
+        };
+
+        Self {
+            translation: transform.translation,
+            rotation: transform.rotation,
+            fov,
+        }
+    }
+
+    pub fn lerp(&self, other: &Self, t: f32) -> Self {
+        Self {
+            translation: self.translation.lerp(other.translation, t),
+            rotation: self.rotation.slerp(other.rotation, t),
+            fov: self.fov + (other.fov - self.fov) * t,
+        }
+    }
+
+    pub fn apply(&self, transform: &mut Transform, projection: &mut Projection) {
+        transform.translation = self.translation;
+        transform.rotation = self.rotation;
+        if let Projection::Perspective(perspective) = projection {
+            perspective.fov = self.fov;
+        }
+    }
+}
+
+/// A snapshot of the camera and the render settings that can be written to
+/// and read from disk (as ron).
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ScenePreset {
+    pub name: String,
+    pub camera: CameraPose,
+    /// Asset path of the skydome, the handle in the render config is not serialized.
+    pub skydome: Option<String>,
+    pub render_config: RenderConfig,
+}
+
+impl ScenePreset {
+    pub fn capture(
+        name: impl Into<String>,
+        camera: CameraPose,
+        render_config: &RenderConfig,
+        asset_server: &AssetServer,
+    ) -> Self {
+        let skydome = render_config
+            .skydome
+            .as_ref()
+            .and_then(|skydome| asset_server.get_path(skydome.id()))
+            .map(|path| path.to_string());
+
+        Self {
+            name: name.into(),
+            camera,
+            skydome,
+            render_config: render_config.clone(),
+        }
+    }
+}
+
+pub fn save_preset(path: impl AsRef<Path>, preset: &ScenePreset) -> Result<(), PresetError> {
+    let contents = ron::ser::to_string_pretty(preset, ron::ser::PrettyConfig::default())?;
+    std::fs::write(path, contents)?;
+    Ok(())
+}
+
+pub fn load_preset(path: impl AsRef<Path>) -> Result<ScenePreset, PresetError> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(ron::from_str(&contents)?)
+}
+
+/// Moves the camera and render settings to the preset, interpolating
+/// over `duration` seconds. A duration of 0 applies the preset immediately.
+#[derive(Event, Clone)]
+pub struct ApplyPreset {
+    pub preset: ScenePreset,
+    pub duration: f32,
+}
+
+#[derive(Resource)]
+struct PresetTransition {
+    from_camera: CameraPose,
+    to_camera: CameraPose,
+    from_config: RenderConfig,
+    to_config: RenderConfig,
+    elapsed: f32,
+    duration: f32,
+}
+
+pub struct PresetPlugin;
+
+impl Plugin for PresetPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<ApplyPreset>();
+        app.add_systems(
+            Update,
+            (start_preset_transition, update_preset_transition).chain(),
+        );
+    }
+}
+
+fn start_preset_transition(
+    mut commands: Commands,
+    mut events: EventReader<ApplyPreset>,
+    asset_server: Res<AssetServer>,
+    render_config: Res<RenderConfig>,
+    cameras: Query<(&Transform, &Projection), With<Camera3d>>,
+) {
+    let Some(ApplyPreset { preset, duration }) = events.read().last() else {
+        return;
+    };
+
+    // the pipelines are not part of the preset
+    let to_config = RenderConfig {
+        rtx_pipeline: render_config.rtx_pipeline.clone(),
+        postprocess_pipeline: render_config.postprocess_pipeline.clone(),
+        upscale_pipeline: render_config.upscale_pipeline.clone(),
+        skydome: preset.skydome.as_ref().map(|path| asset_server.load(path)),
+        ..preset.render_config.clone()
+    };
+
+    let from_camera = cameras
+        .get_single()
+        .map(|(transform, projection)| CameraPose::from_camera(transform, projection))
+        .unwrap_or(preset.camera);
+
+    commands.insert_resource(PresetTransition {
+        from_camera,
+        to_camera: preset.camera,
+        from_config: render_config.clone(),
+        to_config,
+        elapsed: 0.0,
+        duration: *duration,
+    });
+}
+
+fn update_preset_transition(
+    mut commands: Commands,
+    time: Res<Time>,
+    transition: Option<ResMut<PresetTransition>>,
+    mut render_config: ResMut<RenderConfig>,
+    mut cameras: Query<(&mut Transform, &mut Projection, Option<&mut DebugCamera>), With<Camera3d>>,
+) {
+    let Some(mut transition) = transition else {
+        return;
+    };
+
+    transition.elapsed += time.delta_secs();
+    let t = if transition.duration > 0.0 {
+        (transition.elapsed / transition.duration).min(1.0)
+    } else {
+        1.0
+    };
+    let t = t * t * (3.0 - 2.0 * t);
+
+    let pose = transition.from_camera.lerp(&transition.to_camera, t);
+    for (mut transform, mut projection, debug_camera) in cameras.iter_mut() {
+        pose.apply(&mut transform, &mut projection);
+
+        // the debug camera derives the rotation from yaw and pitch every update
+        if let Some(mut debug_camera) = debug_camera {
+            let (yaw, pitch, _) = pose.rotation.to_euler(EulerRot::YXZ);
+            debug_camera.yaw = yaw;
+            debug_camera.pitch = pitch;
+        }
+    }
+
+    *render_config = RenderConfig {
+        pull_focus: render_config.pull_focus,
+        probe_pixel: render_config.probe_pixel,
+        count_rays: render_config.count_rays,
+        ..lerp_render_config(&transition.from_config, &transition.to_config, t)
+    };
+
+    if t >= 1.0 {
+        commands.remove_resource::<PresetTransition>();
+    }
+}
+
+fn lerp_render_config(from: &RenderConfig, to: &RenderConfig, t: f32) -> RenderConfig {
+    let lerp = |a: f32, b: f32| a + (b - a) * t;
+    // a clamp that is switched on or off snaps to the target
+    let lerp_clamp = |a: Option<f32>, b: Option<f32>| match (a, b) {
+        (Some(a), Some(b)) => Some(lerp(a, b)),
+        _ => b,
+    };
+    let (a, b) = (&from.filmic_curve, &to.filmic_curve);
+
+    RenderConfig {
+        sky_color: from.sky_color.lerp(to.sky_color, t),
+        // accumulating while the camera moves would smear the image
+        accumulate: t >= 1.0 && to.accumulate,
+        gamma: lerp(from.gamma, to.gamma),
+        exposure: lerp(from.exposure, to.exposure),
+        aperture: lerp(from.aperture, to.aperture),
+        focal_plane_tilt: from.focal_plane_tilt.lerp(to.focal_plane_tilt, t),
+        near_blur: lerp(from.near_blur, to.near_blur),
+        far_blur: lerp(from.far_blur, to.far_blur),
+        foginess: lerp(from.foginess, to.foginess),
+        fog_scatter: lerp(from.fog_scatter, to.fog_scatter),
+        sky_brightness: lerp(from.sky_brightness, to.sky_brightness),
+        tonemap_whitepoint: lerp(from.tonemap_whitepoint, to.tonemap_whitepoint),
+        firefly_clamp: lerp_clamp(from.firefly_clamp, to.firefly_clamp),
+        bokeh_clamp: lerp_clamp(from.bokeh_clamp, to.bokeh_clamp),
+        motion_blur: match (from.motion_blur, to.motion_blur) {
+            (Some(a), Some(b)) => Some(MotionBlur {
+                shutter_fraction: lerp(a.shutter_fraction, b.shutter_fraction),
+                ..b
+            }),
+            _ => to.motion_blur,
+        },
+        filmic_curve: FilmicCurve {
+            shoulder_strength: lerp(a.shoulder_strength, b.shoulder_strength),
+            linear_strength: lerp(a.linear_strength, b.linear_strength),
+            linear_angle: lerp(a.linear_angle, b.linear_angle),
+            toe_strength: lerp(a.toe_strength, b.toe_strength),
+            toe_numerator: lerp(a.toe_numerator, b.toe_numerator),
+            toe_denominator: lerp(a.toe_denominator, b.toe_denominator),
+        },
+        ..to.clone()
+    }
+}
@@ -40,6 +40,11 @@ impl PluginGroup for RayDefaultPlugins {
         group = group.add(crate::sphere::SpherePlugin);
         group = group.add(crate::render_texture::RenderTexturePlugin);
         group = group.add(crate::bluenoise_plugin::BlueNoisePlugin);
+        group = group.add(crate::pipeline_statistics::PipelineStatisticsPlugin);
+        group = group.add(crate::pixel_probe::PixelProbePlugin);
+        group = group.add(crate::screenshot::ScreenshotPlugin);
+        group = group.add(crate::motion_blur::MotionBlurPlugin);
+        group = group.add(crate::preset::PresetPlugin);
 
         group
     }
@@ -3,10 +3,12 @@ use bevy::{
     ecs::schedule::ScheduleLabel,
     prelude::*,
     render::RenderApp,
-    window::{RawHandleWrapperHolder, WindowCloseRequested, WindowResized},
+    window::{RawHandleWrapperHolder, WindowCloseRequested, WindowMode, WindowResized},
     winit::WakeUp,
 };
 use raw_window_handle::HasDisplayHandle;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
 use winit::event_loop::EventLoop;
 
 use ash::vk;
@@ -14,25 +16,107 @@ use ash::vk;
 use crate::{
     bluenoise_plugin::BlueNoiseBuffer,
     extract::Extract,
-    post_process_filter::PostProcessFilter,
+    motion_blur::{lerp_affine, MotionBlurTLAS, PreviousGlobalTransform},
+    pipeline_statistics::{
+        PipelineStatistics, POST_PROCESS_QUERY, TRACE_BEGIN_TIMESTAMP, TRACE_END_TIMESTAMP,
+        TRACE_QUERY,
+    },
+    pixel_probe::PixelProbe,
+    post_process_filter::{CompiledPostProcessFilter, PostProcessFilter},
     raytracing_pipeline::{RaytracingPipeline, RaytracingPushConstants},
     render_buffer::{Buffer, BufferProvider},
     render_device::RenderDevice,
     render_env::WHITE_TEXTURE_IDX,
     sbt::SBT,
+    screenshot::PendingScreenshots,
     tlas_builder::TLAS,
     vk_init, vk_utils,
-    vulkan_asset::VulkanAssets,
+    vulkan_asset::{VulkanAssetProgress, VulkanAssets},
 };
 
-#[derive(Resource, Clone)]
+/// Asset handles are skipped when (de)serializing, a [`crate::preset::ScenePreset`]
+/// stores the skydome by path instead.
+#[derive(Resource, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct RenderConfig {
+    #[serde(skip)]
     pub rtx_pipeline: Handle<RaytracingPipeline>,
+    #[serde(skip)]
     pub postprocess_pipeline: Handle<PostProcessFilter>,
+    /// Spatial upscaler used when the render target is smaller than the window.
+    #[serde(skip)]
+    pub upscale_pipeline: Handle<PostProcessFilter>,
+    #[serde(skip)]
     pub skydome: Option<Handle<bevy::prelude::Image>>,
     pub sky_color: Vec4,
     pub accumulate: bool,
+    #[serde(skip)]
     pub pull_focus: Option<(u32, u32)>,
+    /// Window pixel read back by the [`crate::pixel_probe::PixelProbe`], alt + click.
+    #[serde(skip)]
+    pub probe_pixel: Option<(u32, u32)>,
+    pub gamma: f32,
+    /// Ignored when a [`PhysicalCamera`] is set.
+    pub exposure: f32,
+    /// Radius of the lens in world units, ignored when a [`PhysicalCamera`] is set.
+    pub aperture: f32,
+    pub physical_camera: Option<PhysicalCamera>,
+    /// Rotation of the focal plane (radians) around the vertical and horizontal axis,
+    /// zero keeps it perpendicular to the view direction.
+    pub focal_plane_tilt: Vec2,
+    /// Scales the aperture for samples that hit in front of the focus.
+    pub near_blur: f32,
+    /// Scales the aperture for samples that hit behind the focus.
+    pub far_blur: f32,
+    pub foginess: f32,
+    pub fog_scatter: f32,
+    pub sky_brightness: f32,
+    pub tonemapper: Tonemapper,
+    /// Linear scene value that maps to pure white with the [`Tonemapper::Hable`] curve.
+    pub tonemap_whitepoint: f32,
+    pub filmic_curve: FilmicCurve,
+    pub ray_flags: RayFlags,
+    /// Resolution of the ray traced image relative to the window.
+    pub render_scale: f32,
+    /// Use the `upscale_pipeline` instead of bilinear sampling when the render
+    /// target is smaller than the window.
+    pub upscale: bool,
+    /// Offset the blue noise lookup by a random amount of pixels every frame.
+    pub bluenoise_scroll: bool,
+    /// Shift all blue noise samples by a random vector every frame (Cranley-Patterson
+    /// rotation), this keeps the noise blue within a frame but decorrelates consecutive
+    /// frames once the 64 slices have been used up.
+    pub bluenoise_rotation: bool,
+    /// Stop tracing once this many frames have been accumulated, the image
+    /// stays on screen. Every frame traces 2 paths per pixel.
+    pub max_samples: Option<u32>,
+    /// Surface bounces (including reflections off glass) after which a path is
+    /// terminated, paths are never longer than 64 interactions in total.
+    pub max_reflection_bounces: u32,
+    /// Transmissions through glass after which a path is terminated.
+    pub max_refraction_bounces: u32,
+    pub debug_view: DebugView,
+    /// Candidate intersections per pixel shown as red by [`DebugView::TraversalHeatmap`].
+    pub heatmap_max: u32,
+    pub motion_blur: Option<MotionBlur>,
+    /// The order in which the trace visits the pixels.
+    pub pixel_order: PixelOrder,
+    /// Render a 360x180 degree equirectangular panorama around the camera instead
+    /// of using its projection, the window should have an aspect ratio of 2:1.
+    pub panorama: bool,
+    /// Limits the luminance of the light a path gathers after bouncing off a surface,
+    /// trading a bit of energy for fewer fireflies.
+    pub firefly_clamp: Option<f32>,
+    /// Limits the luminance of the emitters and sky seen directly by the camera. Out
+    /// of focus these are the bokeh highlights, which the firefly clamp leaves alone.
+    pub bokeh_clamp: Option<f32>,
+    /// Instances (meshes, models and spheres) in the acceleration structure, the ones
+    /// furthest from the camera are left out beyond this. The device limit always applies.
+    pub max_instances: u32,
+    /// Count the rays and any hit candidates in the shaders for the pipeline statistics,
+    /// the atomics slow down the trace so this is off by default.
+    #[serde(skip)]
+    pub count_rays: bool,
 }
 
 impl Default for RenderConfig {
@@ -40,10 +124,271 @@ impl Default for RenderConfig {
         Self {
             rtx_pipeline: Default::default(),
             postprocess_pipeline: Default::default(),
+            upscale_pipeline: Default::default(),
             skydome: Default::default(),
             sky_color: Vec4::splat(1.0),
             accumulate: Default::default(),
             pull_focus: Default::default(),
+            probe_pixel: None,
+            gamma: 2.4,
+            exposure: 1.0,
+            aperture: 0.008,
+            physical_camera: None,
+            focal_plane_tilt: Vec2::ZERO,
+            near_blur: 1.0,
+            far_blur: 1.0,
+            foginess: 0.001,
+            fog_scatter: 0.9,
+            sky_brightness: 1.0,
+            tonemapper: Default::default(),
+            tonemap_whitepoint: 11.2,
+            filmic_curve: Default::default(),
+            ray_flags: Default::default(),
+            render_scale: 1.0,
+            upscale: true,
+            bluenoise_scroll: true,
+            bluenoise_rotation: true,
+            max_samples: None,
+            max_reflection_bounces: 64,
+            max_refraction_bounces: 64,
+            debug_view: Default::default(),
+            heatmap_max: 32,
+            motion_blur: None,
+            pixel_order: Default::default(),
+            panorama: false,
+            firefly_clamp: None,
+            bokeh_clamp: None,
+            max_instances: 100_000,
+            count_rays: false,
+        }
+    }
+}
+
+impl RenderConfig {
+    /// Copies the fields that differ between `before` and `after` onto this config and
+    /// leaves the others alone, so an edit made to an older copy (like the one the dev
+    /// ui renders with) does not revert what changed in the meantime.
+    pub fn apply_edit(&mut self, before: &RenderConfig, after: &RenderConfig) {
+        macro_rules! apply_changed {
+            ($($field:ident),* $(,)?) => {
+                // fails to compile when a field is missing from the list
+                let RenderConfig { $($field: _),* } = after;
+                $(
+                    if before.$field != after.$field {
+                        self.$field = after.$field.clone();
+                    }
+                )*
+            };
+        }
+
+        apply_changed!(
+            rtx_pipeline,
+            postprocess_pipeline,
+            upscale_pipeline,
+            skydome,
+            sky_color,
+            accumulate,
+            pull_focus,
+            probe_pixel,
+            gamma,
+            exposure,
+            aperture,
+            physical_camera,
+            focal_plane_tilt,
+            near_blur,
+            far_blur,
+            foginess,
+            fog_scatter,
+            sky_brightness,
+            tonemapper,
+            tonemap_whitepoint,
+            filmic_curve,
+            ray_flags,
+            render_scale,
+            upscale,
+            bluenoise_scroll,
+            bluenoise_rotation,
+            max_samples,
+            max_reflection_bounces,
+            max_refraction_bounces,
+            debug_view,
+            heatmap_max,
+            motion_blur,
+            pixel_order,
+            panorama,
+            firefly_clamp,
+            bokeh_clamp,
+            max_instances,
+            count_rays,
+        );
+    }
+}
+
+/// Derives the exposure and the depth of field aperture from camera settings.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PhysicalCamera {
+    pub iso: f32,
+    /// In seconds.
+    pub shutter_speed: f32,
+    pub f_stop: f32,
+    /// In world units (meters), only used to size the aperture.
+    pub focal_length: f32,
+}
+
+impl Default for PhysicalCamera {
+    fn default() -> Self {
+        Self {
+            iso: 100.0,
+            shutter_speed: 1.0 / 60.0,
+            f_stop: 2.8,
+            focal_length: 0.045,
+        }
+    }
+}
+
+impl PhysicalCamera {
+    /// Exposure value at ISO 100.
+    pub fn ev100(&self) -> f32 {
+        (self.f_stop * self.f_stop / self.shutter_speed * 100.0 / self.iso).log2()
+    }
+
+    /// The scene has no physical units, so the exposure is relative to
+    /// the default camera, which maps to an exposure of 1.
+    pub fn exposure(&self) -> f32 {
+        // H = 1 / (1.2 * 2^EV100), the 1.2 cancels out in the ratio
+        2.0_f32.powf(Self::default().ev100() - self.ev100())
+    }
+
+    /// Radius of the lens, the diameter is the focal length over the f-stop.
+    pub fn aperture(&self) -> f32 {
+        self.focal_length / self.f_stop * 0.5
+    }
+}
+
+/// Traces every frame at several points in time while the shutter is open, between
+/// the previous and the current transforms of the camera and the instances
+/// (see [`crate::motion_blur::PreviousGlobalTransform`]).
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MotionBlur {
+    /// Part of the time between the previous and the current frame the shutter is
+    /// open for, 0.5 is a 180 degree shutter.
+    pub shutter_fraction: f32,
+    /// Traces per frame, each with the TLAS rebuilt at a jittered time within its
+    /// own part of the shutter interval.
+    pub samples: u32,
+}
+
+impl Default for MotionBlur {
+    fn default() -> Self {
+        Self {
+            shutter_fraction: 0.5,
+            samples: 4,
+        }
+    }
+}
+
+/// The tonemapping operator applied by the post process filter.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Tonemapper {
+    #[default]
+    Aces,
+    /// John Hable's filmic curve (Uncharted 2), shaped by [`FilmicCurve`].
+    Hable,
+}
+
+/// Replaces the path traced image with diagnostics, shown without tonemapping.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DebugView {
+    #[default]
+    None,
+    /// False color of the number of candidate intersections (any hit invocations) of
+    /// the primary ray through the whole scene, a proxy for the traversal cost.
+    TraversalHeatmap,
+}
+
+/// Maps the launch ids of the trace to pixels. Keeping consecutive invocations on
+/// neighbouring pixels can make the incoherent bounces cheaper, depending on the GPU.
+/// The average trace time of each under pipeline statistics in the dev ui shows which
+/// one is fastest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PixelOrder {
+    /// The launch id is the pixel.
+    #[default]
+    Linear,
+    /// 8x8 tiles, row by row within a tile.
+    Tiled,
+    /// 8x8 tiles, in Z-order within a tile.
+    Morton,
+}
+
+impl PixelOrder {
+    pub const ALL: [PixelOrder; 3] = [Self::Linear, Self::Tiled, Self::Morton];
+
+    /// The tiled orders launch whole tiles, the invocations outside of the image do nothing.
+    pub fn launch_extent(&self, extent: vk::Extent2D) -> vk::Extent2D {
+        match self {
+            Self::Linear => extent,
+            Self::Tiled | Self::Morton => vk::Extent2D {
+                width: extent.width.next_multiple_of(8),
+                height: extent.height.next_multiple_of(8),
+            },
+        }
+    }
+}
+
+/// `gl_RayFlags*EXT` bitmasks passed to `traceRayEXT` for each kind of ray, combine
+/// the associated constants.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RayFlags {
+    /// Rays leaving the camera and the focus probe.
+    pub primary: u32,
+    /// All bounces after the primary hit.
+    pub gi: u32,
+}
+
+impl RayFlags {
+    pub const NONE: u32 = 0x0;
+    pub const OPAQUE: u32 = 0x1;
+    pub const NO_OPAQUE: u32 = 0x2;
+    pub const TERMINATE_ON_FIRST_HIT: u32 = 0x4;
+    pub const SKIP_CLOSEST_HIT_SHADER: u32 = 0x8;
+    pub const CULL_BACK_FACING_TRIANGLES: u32 = 0x10;
+    pub const CULL_FRONT_FACING_TRIANGLES: u32 = 0x20;
+    pub const CULL_OPAQUE: u32 = 0x40;
+    pub const CULL_NO_OPAQUE: u32 = 0x80;
+    pub const SKIP_TRIANGLES: u32 = 0x100;
+    pub const SKIP_AABBS: u32 = 0x200;
+}
+
+impl Default for RayFlags {
+    fn default() -> Self {
+        Self {
+            primary: Self::OPAQUE,
+            gi: Self::OPAQUE,
+        }
+    }
+}
+
+/// Parameters of the Hable filmic curve, the defaults match the original presentation.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FilmicCurve {
+    pub shoulder_strength: f32,
+    pub linear_strength: f32,
+    pub linear_angle: f32,
+    pub toe_strength: f32,
+    pub toe_numerator: f32,
+    pub toe_denominator: f32,
+}
+
+impl Default for FilmicCurve {
+    fn default() -> Self {
+        Self {
+            shoulder_strength: 0.15,
+            linear_strength: 0.50,
+            linear_angle: 0.10,
+            toe_strength: 0.20,
+            toe_numerator: 0.02,
+            toe_denominator: 0.30,
         }
     }
 }
@@ -63,6 +408,57 @@ pub struct UniformData {
     foginess: f32,
     fog_scatter: f32,
     sky_brightness: f32,
+    tonemapper: u32,
+    tonemap_whitepoint: f32,
+    shoulder_strength: f32,
+    linear_strength: f32,
+    linear_angle: f32,
+    toe_strength: f32,
+    toe_numerator: f32,
+    toe_denominator: f32,
+    primary_ray_flags: u32,
+    gi_ray_flags: u32,
+    bluenoise_offset_x: u32,
+    bluenoise_offset_y: u32,
+    bluenoise_shift_x: f32,
+    bluenoise_shift_y: f32,
+    /// Whether the slices may be reused after the first 64 samples.
+    bluenoise_decorrelated: u32,
+    focal_plane_tilt_x: f32,
+    focal_plane_tilt_y: f32,
+    near_blur: f32,
+    far_blur: f32,
+    max_reflection_bounces: u32,
+    max_refraction_bounces: u32,
+    debug_view: u32,
+    heatmap_max: u32,
+    time_samples: u32,
+    motion_blur_padding: u32,
+    shutter_open_inverse_view: Mat4,
+    panorama: u32,
+    /// 0 disables the clamp.
+    firefly_clamp: f32,
+    /// 0 disables the clamp.
+    bokeh_clamp: f32,
+}
+
+/// The accumulation state of the last rendered frame, shared between the main
+/// and the render app.
+#[derive(Resource, Clone, Default)]
+pub struct AccumulationStatus(Arc<Mutex<AccumulatedFrames>>);
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AccumulatedFrames {
+    /// Number of frames summed in the render target.
+    pub frames: u32,
+    /// Camera transform (inverse view matrix) the frames were traced from.
+    pub view: Mat4,
+}
+
+impl AccumulationStatus {
+    pub fn get(&self) -> AccumulatedFrames {
+        *self.0.lock().unwrap()
+    }
 }
 
 #[repr(C)]
@@ -109,15 +505,48 @@ fn shutdown_render_app(world: &mut World) {
                 unsafe { render_device.queue_wait_idle(*queue).unwrap() };
             }
             world.run_schedule(TeardownSchedule);
+            // only now the device goes, so that teardown systems can rely on it.
+            on_shutdown(world);
             log::info!("RenderApp has shut down, sending ack to main app");
             killswitch.send_res_close.send(()).unwrap();
         }
     });
 }
 
+/// Runs once in the render app when it shuts down. The device is idle and
+/// still present, see [`RenderAppExt::add_render_teardown`].
 #[derive(ScheduleLabel, PartialEq, Eq, Debug, Clone, Hash)]
 pub struct TeardownSchedule;
 
+/// Runs once in the render app before the first frame, the device is present.
+/// See [`RenderAppExt::add_render_startup`].
+#[derive(ScheduleLabel, PartialEq, Eq, Debug, Clone, Hash)]
+pub struct RenderStartupSchedule;
+
+pub trait RenderAppExt {
+    /// Adds systems to the render app that run once before the first frame, use
+    /// these to allocate gpu resources.
+    fn add_render_startup<M>(&mut self, systems: impl IntoSystemConfigs<M>) -> &mut Self;
+
+    /// Adds systems to the render app that run once when it shuts down, use these
+    /// to free gpu resources. The `RenderDevice` is guaranteed to still be present.
+    fn add_render_teardown<M>(&mut self, systems: impl IntoSystemConfigs<M>) -> &mut Self;
+}
+
+impl RenderAppExt for App {
+    fn add_render_startup<M>(&mut self, systems: impl IntoSystemConfigs<M>) -> &mut Self {
+        self.sub_app_mut(RenderApp)
+            .add_systems(RenderStartupSchedule, systems);
+        self
+    }
+
+    fn add_render_teardown<M>(&mut self, systems: impl IntoSystemConfigs<M>) -> &mut Self {
+        self.sub_app_mut(RenderApp)
+            .add_systems(TeardownSchedule, systems);
+        self
+    }
+}
+
 #[derive(ScheduleLabel, Debug, Hash, PartialEq, Eq, Clone)]
 pub struct Render;
 
@@ -185,11 +614,23 @@ impl Plugin for RayRenderPlugin {
             recv_res_close,
         });
 
+        app.add_systems(PostStartup, warn_missing_pipelines);
         app.add_systems(
             Update,
-            (close_when_requested, handle_input, set_focus_pulling),
+            (
+                close_when_requested,
+                handle_input,
+                set_focus_pulling,
+                warn_panorama_aspect,
+            ),
         );
 
+        app.init_resource::<WindowMonitor>();
+        let accumulation_status = AccumulationStatus::default();
+        app.insert_resource(accumulation_status.clone());
+        #[cfg(target_os = "windows")]
+        app.add_systems(Update, track_window_monitor);
+
         let mut render_app = SubApp::new();
         render_app.update_schedule = Some(Render.intern());
 
@@ -200,6 +641,7 @@ impl Plugin for RayRenderPlugin {
                 recv_req_close,
             });
         render_app.world_mut().init_resource::<RenderConfig>();
+        render_app.insert_resource(accumulation_status);
 
         let event_loop = app
             .world()
@@ -218,21 +660,21 @@ impl Plugin for RayRenderPlugin {
         render_app.add_event::<WindowResized>();
         render_app.insert_resource(sphere_blas);
         render_app.insert_resource(render_device.clone());
+        app.insert_resource(render_device.properties());
         render_app.init_resource::<Frame>();
 
         app.init_resource::<ScratchMainWorld>();
 
-        let extract_schedule = Schedule::new(ExtractSchedule);
-        let mut teardown_schedule = Schedule::new(TeardownSchedule);
-        teardown_schedule.add_systems(on_shutdown);
-
-        render_app.add_schedule(extract_schedule);
-        render_app.add_schedule(teardown_schedule);
+        render_app.add_schedule(Schedule::new(ExtractSchedule));
+        render_app.add_schedule(Schedule::new(RenderStartupSchedule));
+        render_app.add_schedule(Schedule::new(TeardownSchedule));
         render_app.add_schedule(Render::base_schedule());
 
         render_app.add_systems(
             Render,
-            apply_extract_commands.in_set(RenderSet::ExtractCommands),
+            (apply_extract_commands, run_render_startup)
+                .chain()
+                .in_set(RenderSet::ExtractCommands),
         );
 
         render_app.add_systems(
@@ -292,6 +734,13 @@ fn extract(main_world: &mut World, render_world: &mut World) {
     main_world.insert_resource(ScratchMainWorld(scratch_world));
 }
 
+fn run_render_startup(world: &mut World, mut has_run: Local<bool>) {
+    if !*has_run {
+        *has_run = true;
+        world.run_schedule(RenderStartupSchedule);
+    }
+}
+
 /// Applies the commands from the extract schedule. This happens during
 /// the render schedule rather than during extraction to allow the commands to run in parallel with the
 /// main app when pipelined rendering is enabled.
@@ -308,10 +757,45 @@ fn apply_extract_commands(render_world: &mut World) {
 pub struct ExtractedWindow {
     pub width: u32,
     pub height: u32,
+    pub exclusive_fullscreen: bool,
+    pub hmonitor: isize,
+    /// The window has no area, nothing is rendered until it is restored.
+    pub minimized: bool,
+}
+
+/// The monitor the primary window is on, required to create an exclusive
+/// fullscreen swapchain. Only tracked on windows, 0 means unknown.
+#[derive(Resource, Default, Clone, Copy)]
+pub struct WindowMonitor {
+    pub hmonitor: isize,
+}
+
+#[cfg(target_os = "windows")]
+fn track_window_monitor(
+    windows: Query<Entity, With<bevy::window::PrimaryWindow>>,
+    winit_windows: NonSend<bevy::winit::WinitWindows>,
+    mut monitor: ResMut<WindowMonitor>,
+) {
+    use winit::platform::windows::MonitorHandleExtWindows;
+
+    let Ok(window) = windows.get_single() else {
+        return;
+    };
+
+    let hmonitor = winit_windows
+        .get_window(window)
+        .and_then(|window| window.current_monitor())
+        .map(|monitor| monitor.hmonitor() as isize)
+        .unwrap_or(0);
+
+    if monitor.hmonitor != hmonitor {
+        monitor.hmonitor = hmonitor;
+    }
 }
 
 fn extract_primary_window(
     windows: Extract<Query<(&Window, &RawHandleWrapperHolder)>>,
+    monitor: Extract<Res<WindowMonitor>>,
     mut resized_events: Extract<EventReader<WindowResized>>,
     mut write: EventWriter<WindowResized>,
     mut commands: Commands,
@@ -335,6 +819,10 @@ fn extract_primary_window(
     commands.insert_resource(ExtractedWindow {
         width: window.resolution.width().max(1.0) as u32,
         height: window.resolution.height().max(1.0) as u32,
+        exclusive_fullscreen: matches!(window.mode, WindowMode::Fullscreen(..)),
+        hmonitor: monitor.hmonitor,
+        minimized: window.resolution.physical_width() == 0
+            || window.resolution.physical_height() == 0,
     });
 
     for event in resized_events.read() {
@@ -352,17 +840,21 @@ fn extract_render_config(
             &Projection,
             &Transform,
             &GlobalTransform,
+            Option<&PreviousGlobalTransform>,
         )>,
     >,
 ) {
     commands.insert_resource(render_config.clone());
-    for (camera, camera3d, projection, transform, global_transform) in cameras.iter() {
+    for (camera, camera3d, projection, transform, global_transform, previous) in cameras.iter() {
         commands.spawn((
             camera.clone(),
             camera3d.clone(),
             projection.clone(),
             transform.clone(),
             global_transform.clone(),
+            previous
+                .copied()
+                .unwrap_or(PreviousGlobalTransform(*global_transform)),
         ));
     }
 }
@@ -371,10 +863,12 @@ fn extract_time(mut commands: Commands, time: Extract<Res<Time>>) {
     commands.insert_resource(time.clone());
 }
 
+/// Alt + click probes the pixel instead of focusing on it.
 fn set_focus_pulling(
     windows: Query<&Window>,
     mut render_config: ResMut<RenderConfig>,
     mouse: Res<ButtonInput<MouseButton>>,
+    keyboard: Res<ButtonInput<KeyCode>>,
 ) {
     let window = windows.single();
     render_config.pull_focus = None;
@@ -382,12 +876,60 @@ fn set_focus_pulling(
     if let Some(mouse_pos) = window.physical_cursor_position() {
         let x = mouse_pos.x as u32;
         let y = mouse_pos.y as u32;
-        if mouse.pressed(MouseButton::Left) {
+        if keyboard.any_pressed([KeyCode::AltLeft, KeyCode::AltRight]) {
+            if mouse.just_pressed(MouseButton::Left) {
+                render_config.probe_pixel = Some((x, y));
+            }
+        } else if mouse.pressed(MouseButton::Left) {
             render_config.pull_focus = Some((x, y));
         }
     }
 }
 
+/// Without the pipelines nothing is traced or presented, the `DevShaderPlugin` sets
+/// up the default ones.
+fn warn_missing_pipelines(render_config: Res<RenderConfig>) {
+    if render_config.rtx_pipeline == Handle::default() {
+        log::warn!(
+            "No raytracing pipeline configured (RenderConfig::rtx_pipeline), the scene will not render"
+        );
+    }
+    if render_config.postprocess_pipeline == Handle::default() {
+        log::warn!(
+            "No post process pipeline configured (RenderConfig::postprocess_pipeline), nothing will be presented"
+        );
+    }
+    if render_config.upscale && render_config.upscale_pipeline == Handle::default() {
+        log::warn!(
+            "No upscale pipeline configured (RenderConfig::upscale_pipeline), upscaling falls back to bilinear sampling"
+        );
+    }
+}
+
+/// The panorama is stretched when the window is not twice as wide as it is high.
+fn warn_panorama_aspect(
+    windows: Query<&Window>,
+    render_config: Res<RenderConfig>,
+    mut warned: Local<bool>,
+) {
+    if !render_config.panorama {
+        *warned = false;
+        return;
+    }
+
+    let Ok(window) = windows.get_single() else {
+        return;
+    };
+    let aspect = window.width() / window.height();
+    if !*warned && (aspect - 2.0).abs() > 0.01 {
+        log::warn!(
+            "Rendering a panorama with an aspect ratio of {:.2} instead of 2:1, it will be stretched",
+            aspect
+        );
+        *warned = true;
+    }
+}
+
 #[derive(Resource, Default)]
 pub struct Frame {
     pub swapchain_image: vk::Image,
@@ -400,31 +942,48 @@ pub struct Frame {
 #[derive(Default)]
 pub struct RenderFrameBuffers {
     pub main: (vk::Image, vk::ImageView),
+    /// Output of the post process filter when it is followed by an upscale pass.
+    pub post_processed: (vk::Image, vk::ImageView),
+    pub extent: vk::Extent2D,
 }
 
 impl RenderFrameBuffers {
     pub unsafe fn prepare(
         &mut self,
         render_device: &RenderDevice,
-        swapchain: &crate::swapchain::Swapchain,
+        extent: vk::Extent2D,
         cmd_buffer: vk::CommandBuffer,
     ) {
-        // (Re)create the render target if needed
-        if self.main.0 == vk::Image::null() || swapchain.resized {
+        // (Re)create the render targets if needed
+        if self.main.0 == vk::Image::null() || self.extent != extent {
             log::trace!("(Re)creating render target");
-            render_device.destroyer.destroy_image_view(self.main.1);
-            render_device.destroyer.destroy_image(self.main.0);
+            self.destroy(render_device);
+            self.extent = extent;
+
             let image_info = vk_init::image_info(
-                swapchain.swapchain_extent.width,
-                swapchain.swapchain_extent.height,
+                extent.width,
+                extent.height,
                 vk::Format::R32G32B32A32_SFLOAT,
-                vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
+                vk::ImageUsageFlags::STORAGE
+                    | vk::ImageUsageFlags::SAMPLED
+                    | vk::ImageUsageFlags::TRANSFER_SRC,
             );
             self.main.0 = render_device.create_render_target(&image_info);
 
             let view_info = vk_init::image_view_info(self.main.0, image_info.format);
             self.main.1 = render_device.create_image_view(&view_info, None).unwrap();
 
+            let image_info = vk_init::image_info(
+                extent.width,
+                extent.height,
+                vk::Format::B8G8R8A8_UNORM,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+            );
+            self.post_processed.0 = render_device.create_render_target(&image_info);
+
+            let view_info = vk_init::image_view_info(self.post_processed.0, image_info.format);
+            self.post_processed.1 = render_device.create_image_view(&view_info, None).unwrap();
+
             // Transition to render target to general
             vk_utils::transition_image_layout(
                 &render_device,
@@ -439,9 +998,105 @@ impl RenderFrameBuffers {
     pub fn destroy(&mut self, render_device: &RenderDevice) {
         render_device.destroyer.destroy_image_view(self.main.1);
         render_device.destroyer.destroy_image(self.main.0);
+        render_device
+            .destroyer
+            .destroy_image_view(self.post_processed.1);
+        render_device.destroyer.destroy_image(self.post_processed.0);
+    }
+}
+
+/// Forcing rays to be opaque would skip the any hit shader, which alpha masked
+/// geometry and faded instances need.
+fn opacity_ray_flags(flags: u32, tlas: &TLAS) -> u32 {
+    if tlas.has_faded_instances || tlas.has_non_opaque_geometry {
+        flags & !RayFlags::OPAQUE
+    } else {
+        flags
     }
 }
 
+/// Begins dynamic rendering into `view` with a viewport covering all of `extent`.
+unsafe fn begin_rendering(
+    render_device: &RenderDevice,
+    cmd_buffer: vk::CommandBuffer,
+    view: vk::ImageView,
+    extent: vk::Extent2D,
+) {
+    let render_area = vk::Rect2D::default().extent(extent);
+
+    let attachment_info = vk::RenderingAttachmentInfo::default()
+        .image_view(view)
+        .image_layout(vk::ImageLayout::ATTACHMENT_OPTIMAL)
+        .load_op(vk::AttachmentLoadOp::CLEAR)
+        .store_op(vk::AttachmentStoreOp::STORE);
+
+    let render_info = vk::RenderingInfo::default()
+        .layer_count(1)
+        .render_area(render_area)
+        .color_attachments(std::slice::from_ref(&attachment_info));
+
+    render_device.cmd_begin_rendering(cmd_buffer, &render_info);
+
+    render_device.cmd_set_scissor(cmd_buffer, 0, std::slice::from_ref(&render_area));
+    render_device.cmd_set_viewport(
+        cmd_buffer,
+        0,
+        std::slice::from_ref(
+            &vk::Viewport::default()
+                .width(extent.width as f32)
+                .height(extent.height as f32)
+                .min_depth(0.0)
+                .max_depth(1.0),
+        ),
+    );
+}
+
+/// Draws a fullscreen triangle with the filter, which samples `source` at binding 0.
+unsafe fn draw_filter(
+    render_device: &RenderDevice,
+    cmd_buffer: vk::CommandBuffer,
+    filter: &CompiledPostProcessFilter,
+    descriptor_set: vk::DescriptorSet,
+    source: vk::ImageView,
+    source_layout: vk::ImageLayout,
+    uniform_buffer: u64,
+) {
+    render_device.cmd_bind_pipeline(cmd_buffer, vk::PipelineBindPoint::GRAPHICS, filter.pipeline);
+
+    render_device.cmd_push_constants(
+        cmd_buffer,
+        filter.pipeline_layout,
+        vk::ShaderStageFlags::ALL,
+        0,
+        bytemuck::cast_slice(&[uniform_buffer]),
+    );
+
+    // Ensure the descriptor set is up to date
+    let source_binding = vk::DescriptorImageInfo::default()
+        .image_layout(source_layout)
+        .image_view(source)
+        .sampler(render_device.linear_sampler);
+
+    let writes = [vk::WriteDescriptorSet::default()
+        .dst_set(descriptor_set)
+        .dst_binding(0)
+        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+        .image_info(std::slice::from_ref(&source_binding))];
+
+    render_device.update_descriptor_sets(&writes, &[]);
+
+    render_device.cmd_bind_descriptor_sets(
+        cmd_buffer,
+        vk::PipelineBindPoint::GRAPHICS,
+        filter.pipeline_layout,
+        0,
+        std::slice::from_ref(&descriptor_set),
+        &[],
+    );
+
+    render_device.cmd_draw(cmd_buffer, 3, 1, 0, 0);
+}
+
 fn render_frame(
     render_device: Res<crate::render_device::RenderDevice>,
     window: Res<ExtractedWindow>,
@@ -460,31 +1115,74 @@ fn render_frame(
     bluenoise_buffer: Res<BlueNoiseBuffer>,
     tlas: Res<TLAS>,
     sbt: Res<SBT>,
-    camera: Query<(&Projection, &GlobalTransform), With<Camera>>,
+    camera: Query<
+        (
+            &Projection,
+            &GlobalTransform,
+            Option<&PreviousGlobalTransform>,
+        ),
+        With<Camera>,
+    >,
     mut tick: Local<u32>,
-    time: Res<Time>,
+    (
+        time,
+        mut pipeline_statistics,
+        asset_progress,
+        accumulation_status,
+        mut screenshots,
+        mut motion_blur_tlas,
+        mut pixel_probe,
+    ): (
+        Res<Time>,
+        Option<ResMut<PipelineStatistics>>,
+        Option<Res<VulkanAssetProgress>>,
+        Res<AccumulationStatus>,
+        Option<ResMut<PendingScreenshots>>,
+        Option<ResMut<MotionBlurTLAS>>,
+        Option<ResMut<PixelProbe>>,
+    ),
     mut fps_runnig_avg: Local<f32>,
 ) {
     let Some(mut swapchain) = swapchain else {
         return;
     };
 
-    let (
-        Some(mut dev_ui),
-        Some(mut dev_ui_state),
-        Some(dev_ui_update),
-        Some(dev_ui_platform_output),
-    ) = dev_ui_stuff
-    else {
-        return;
+    // the dev ui is optional, without it the frame is presented as is
+    let mut dev_ui_stuff = match dev_ui_stuff {
+        (Some(dev_ui), Some(dev_ui_state), Some(dev_ui_update), Some(dev_ui_platform_output)) => {
+            Some((dev_ui, dev_ui_state, dev_ui_update, dev_ui_platform_output))
+        }
+        _ => None,
     };
 
-    *tick += 1;
+    // the render target holds tick + 1 frames
+    let converged = render_config.accumulate
+        && render_config
+            .max_samples
+            .is_some_and(|max_samples| *tick + 1 >= max_samples);
+    if !converged {
+        *tick += 1;
+    }
     if !render_config.accumulate {
         *tick = 0;
     }
     let camera = camera.single();
     let inverse_view = camera.1.compute_matrix();
+    let motion_blur = render_config
+        .motion_blur
+        .filter(|motion_blur| motion_blur.samples > 1);
+    let shutter_open_inverse_view = match (motion_blur, camera.2) {
+        (Some(motion_blur), Some(previous)) => Mat4::from(lerp_affine(
+            &previous.0.affine(),
+            &camera.1.affine(),
+            1.0 - motion_blur.shutter_fraction,
+        )),
+        _ => inverse_view,
+    };
+    *accumulation_status.0.lock().unwrap() = AccumulatedFrames {
+        frames: *tick + 1,
+        view: inverse_view,
+    };
     let projection_matrix = match camera.0 {
         Projection::Perspective(perspective) => Mat4::perspective_infinite_reverse_rh(
             perspective.fov,
@@ -539,20 +1237,76 @@ fn render_frame(
             inverse_projection,
             tick: *tick,
             accumulate: if render_config.accumulate { 1 } else { 0 },
+            // the cursor is in window pixels, the render target may be scaled
             pull_focus_x: render_config
                 .pull_focus
-                .map(|(x, _)| x)
+                .map(|(x, _)| (x as f32 * render_config.render_scale) as u32)
                 .unwrap_or(0xFFFFFFFF),
             pull_focus_y: render_config
                 .pull_focus
-                .map(|(_, y)| y)
+                .map(|(_, y)| (y as f32 * render_config.render_scale) as u32)
                 .unwrap_or(0xFFFFFFFF),
-            gamma: dev_ui_state.gamma,
-            exposure: dev_ui_state.exposure,
-            aperture: dev_ui_state.aperture,
-            foginess: dev_ui_state.foginess,
-            fog_scatter: dev_ui_state.fog_scatter,
-            sky_brightness: dev_ui_state.sky_brightness,
+            gamma: render_config.gamma,
+            exposure: render_config
+                .physical_camera
+                .map_or(render_config.exposure, |camera| camera.exposure()),
+            aperture: render_config
+                .physical_camera
+                .map_or(render_config.aperture, |camera| camera.aperture()),
+            foginess: render_config.foginess,
+            fog_scatter: render_config.fog_scatter,
+            sky_brightness: render_config.sky_brightness,
+            tonemapper: render_config.tonemapper as u32,
+            tonemap_whitepoint: render_config.tonemap_whitepoint,
+            shoulder_strength: render_config.filmic_curve.shoulder_strength,
+            linear_strength: render_config.filmic_curve.linear_strength,
+            linear_angle: render_config.filmic_curve.linear_angle,
+            toe_strength: render_config.filmic_curve.toe_strength,
+            toe_numerator: render_config.filmic_curve.toe_numerator,
+            toe_denominator: render_config.filmic_curve.toe_denominator,
+            primary_ray_flags: opacity_ray_flags(render_config.ray_flags.primary, &tlas),
+            gi_ray_flags: opacity_ray_flags(render_config.ray_flags.gi, &tlas),
+            bluenoise_offset_x: if render_config.bluenoise_scroll {
+                rand::random::<u32>() % 128
+            } else {
+                0
+            },
+            bluenoise_offset_y: if render_config.bluenoise_scroll {
+                rand::random::<u32>() % 128
+            } else {
+                0
+            },
+            bluenoise_shift_x: if render_config.bluenoise_rotation {
+                rand::random()
+            } else {
+                0.0
+            },
+            bluenoise_shift_y: if render_config.bluenoise_rotation {
+                rand::random()
+            } else {
+                0.0
+            },
+            bluenoise_decorrelated: if render_config.bluenoise_scroll
+                || render_config.bluenoise_rotation
+            {
+                1
+            } else {
+                0
+            },
+            focal_plane_tilt_x: render_config.focal_plane_tilt.x,
+            focal_plane_tilt_y: render_config.focal_plane_tilt.y,
+            near_blur: render_config.near_blur,
+            far_blur: render_config.far_blur,
+            max_reflection_bounces: render_config.max_reflection_bounces,
+            max_refraction_bounces: render_config.max_refraction_bounces,
+            debug_view: render_config.debug_view as u32,
+            heatmap_max: render_config.heatmap_max,
+            time_samples: motion_blur.map_or(1, |motion_blur| motion_blur.samples),
+            motion_blur_padding: 0,
+            shutter_open_inverse_view,
+            panorama: render_config.panorama as u32,
+            firefly_clamp: render_config.firefly_clamp.unwrap_or(0.0),
+            bokeh_clamp: render_config.bokeh_clamp.unwrap_or(0.0),
         };
 
         let mut mapped = render_device.map_buffer(&mut frame.uniform_buffer);
@@ -560,7 +1314,9 @@ fn render_frame(
     }
 
     unsafe {
-        let (swapchain_image, swapchain_view) = swapchain.aquire_next_image(&window);
+        let Some((swapchain_image, swapchain_view)) = swapchain.aquire_next_image(&window) else {
+            return;
+        };
         render_device.destroyer.tick();
         let cmd_buffer = render_device.command_buffers[swapchain.frame_count % 2];
 
@@ -579,50 +1335,114 @@ fn render_frame(
             )
             .unwrap();
 
+        // the fence of the previous frame has been waited on, so its queries are done.
+        if let Some(statistics) = pipeline_statistics.as_mut() {
+            statistics.read_back(&render_device);
+            statistics.cmd_reset(&render_device, cmd_buffer);
+        }
+        if let Some(probe) = pixel_probe.as_mut() {
+            probe.read_back(&render_device);
+        }
+
+        let render_extent = vk::Extent2D {
+            width: ((swapchain.swapchain_extent.width as f32 * render_config.render_scale) as u32)
+                .max(1),
+            height: ((swapchain.swapchain_extent.height as f32 * render_config.render_scale)
+                as u32)
+                .max(1),
+        };
         frame
             .render_frame_buffers
-            .prepare(&render_device, &swapchain, cmd_buffer);
+            .prepare(&render_device, render_extent, cmd_buffer);
 
-        if let Some(rtx_pipeline) = rtx_pipelines.get(&render_config.rtx_pipeline) {
+        let rtx_pipeline = rtx_pipelines
+            .get(&render_config.rtx_pipeline)
+            .filter(|_| !converged);
+        if let Some(rtx_pipeline) = rtx_pipeline {
             if tlas.acceleration_structure.handle != vk::AccelerationStructureKHR::null()
                 && sbt.data.address != 0
             {
+                let descriptor_set = rtx_pipeline.descriptor_sets[swapchain.frame_count % 2];
+                let sky_texture = match &render_config.skydome {
+                    None => WHITE_TEXTURE_IDX,
+                    Some(skydome) => textures
+                        .get(skydome)
+                        .and_then(Option::as_ref)
+                        .map_or(WHITE_TEXTURE_IDX, |t| {
+                            render_device.register_bindless_texture(&t, WHITE_TEXTURE_IDX)
+                        }),
+                };
+
+                let launch_extent = render_config.pixel_order.launch_extent(render_extent);
+                let ray_counters = pipeline_statistics
+                    .as_ref()
+                    .filter(|_| render_config.count_rays)
+                    .map(|statistics| statistics.ray_counters_address());
+
                 // Ensure the descriptor set is up to date
                 let render_target_main_binding = vk::DescriptorImageInfo::default()
                     .image_layout(vk::ImageLayout::GENERAL)
                     .image_view(frame.render_frame_buffers.main.1);
 
-                let mut ac_binding = vk::WriteDescriptorSetAccelerationStructureKHR::default()
-                    .acceleration_structures(std::slice::from_ref(
-                        &tlas.acceleration_structure.handle,
-                    ));
-
-                let writes = [
-                    vk::WriteDescriptorSet::default()
-                        .dst_set(rtx_pipeline.descriptor_sets[swapchain.frame_count % 2])
-                        .dst_binding(0)
-                        .descriptor_count(1)
-                        .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
-                        .image_info(std::slice::from_ref(&render_target_main_binding)),
-                    vk::WriteDescriptorSet::default()
-                        .dst_set(rtx_pipeline.descriptor_sets[swapchain.frame_count % 2])
-                        .dst_binding(100)
-                        .descriptor_count(1)
-                        .descriptor_type(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR)
-                        .push_next(&mut ac_binding),
-                ];
+                let writes = [vk::WriteDescriptorSet::default()
+                    .dst_set(descriptor_set)
+                    .dst_binding(0)
+                    .descriptor_count(1)
+                    .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                    .image_info(std::slice::from_ref(&render_target_main_binding))];
 
                 render_device.update_descriptor_sets(&writes, &[]);
 
+                if let Some(statistics) = pipeline_statistics.as_mut() {
+                    statistics.traced_pixel_order = Some(render_config.pixel_order);
+                    statistics.cmd_write_timestamp(
+                        &render_device,
+                        cmd_buffer,
+                        TRACE_BEGIN_TIMESTAMP,
+                    );
+                    statistics.cmd_begin(&render_device, cmd_buffer, TRACE_QUERY);
+                }
+
+                // (acceleration structure, time) of every trace, in order
+                let mut traces = vec![(tlas.acceleration_structure.address, 1.0)];
+                if let (Some(motion_blur), Some(motion_blur_tlas)) =
+                    (motion_blur, motion_blur_tlas.as_mut())
+                {
+                    // the previous frame using these is done, see `MotionBlurTLAS`
+                    let motion_tlases = &mut motion_blur_tlas.0
+                        [swapchain.frame_count % crate::swapchain::FRAMES_IN_FLIGHT];
+                    let samples = motion_blur.samples as usize;
+                    if motion_tlases.len() > samples {
+                        for motion_tlas in motion_tlases.drain(samples..) {
+                            motion_tlas.destroy(&render_device);
+                        }
+                    }
+                    motion_tlases.resize_with(samples, TLAS::default);
+
+                    traces = motion_tlases
+                        .iter_mut()
+                        .enumerate()
+                        .map(|(sample, motion_tlas)| {
+                            // jittered within the stratum
+                            let time = (sample as f32 + rand::random::<f32>()) / samples as f32;
+                            let t = 1.0 - motion_blur.shutter_fraction * (1.0 - time);
+                            motion_tlas.cmd_build(
+                                &render_device,
+                                cmd_buffer,
+                                &tlas.instances_at(t),
+                            );
+                            (motion_tlas.acceleration_structure.address, time)
+                        })
+                        .collect();
+                    vk_utils::build_to_trace_barrier(&render_device, cmd_buffer);
+                }
+
                 render_device.cmd_bind_descriptor_sets(
                     cmd_buffer,
                     vk::PipelineBindPoint::RAY_TRACING_KHR,
                     rtx_pipeline.pipeline_layout,
                     0,
-                    &[
-                        rtx_pipeline.descriptor_sets[swapchain.frame_count % 2],
-                        render_device.bindless_descriptor_set,
-                    ],
+                    &[descriptor_set, render_device.bindless_descriptor_set],
                     &[],
                 );
 
@@ -632,41 +1452,83 @@ fn render_frame(
                     rtx_pipeline.pipeline,
                 );
 
-                let push_constants = RaytracingPushConstants {
-                    uniform_buffer: frame.uniform_buffer.address,
-                    material_buffer: tlas.material_buffer.address,
-                    bluenoise_buffer2: bluenoise_buffer.0.address,
-                    focus_buffer: frame.focus_data.address,
-                    sky_texture: match &render_config.skydome {
-                        None => WHITE_TEXTURE_IDX,
-                        Some(skydome) => textures.get(skydome).map_or(WHITE_TEXTURE_IDX, |t| {
-                            render_device.register_bindless_texture(&t)
-                        }),
-                    },
-                    padding: [0; 1],
-                };
-
-                render_device.cmd_push_constants(
-                    cmd_buffer,
-                    rtx_pipeline.pipeline_layout,
-                    vk::ShaderStageFlags::ALL,
-                    0,
-                    bytemuck::cast_slice(&[push_constants]),
-                );
+                // Only the first trace may reset the accumulation, every next one adds
+                // to what the previous one wrote.
+                for (time_sample, (acceleration_structure, time)) in traces.into_iter().enumerate()
+                {
+                    if time_sample > 0 {
+                        vk_utils::trace_to_trace_barrier(&render_device, cmd_buffer);
+                    }
+
+                    let push_constants = RaytracingPushConstants {
+                        uniform_buffer: frame.uniform_buffer.address,
+                        material_buffer: tlas.material_buffer.address,
+                        bluenoise_buffer2: bluenoise_buffer.0.address,
+                        focus_buffer: frame.focus_data.address,
+                        sky_texture,
+                        count_rays: ray_counters.is_some() as u32,
+                        instance_data_buffer: tlas.instance_data_buffer.address,
+                        time,
+                        time_sample: time_sample as u32,
+                        pixel_order: render_config.pixel_order as u32,
+                        padding: [0; 1],
+                        ray_counters: ray_counters.unwrap_or_default(),
+                        acceleration_structure,
+                    };
+
+                    render_device.cmd_push_constants(
+                        cmd_buffer,
+                        rtx_pipeline.pipeline_layout,
+                        vk::ShaderStageFlags::ALL,
+                        0,
+                        bytemuck::cast_slice(&[push_constants]),
+                    );
+
+                    render_device.ext_rtx_pipeline.cmd_trace_rays(
+                        cmd_buffer,
+                        &sbt.raygen_region,
+                        &sbt.miss_region,
+                        &sbt.hit_region,
+                        &vk::StridedDeviceAddressRegionKHR::default(),
+                        launch_extent.width,
+                        launch_extent.height,
+                        1,
+                    );
+                }
+
+                if let Some(statistics) = pipeline_statistics.as_ref() {
+                    statistics.cmd_end(&render_device, cmd_buffer, TRACE_QUERY);
+                    statistics.cmd_write_timestamp(&render_device, cmd_buffer, TRACE_END_TIMESTAMP);
+                }
+            }
+        }
 
-                render_device.ext_rtx_pipeline.cmd_trace_rays(
+        if let (Some(probe), Some((x, y))) = (pixel_probe.as_mut(), render_config.probe_pixel) {
+            let pixel = (
+                (x as f32 * render_config.render_scale) as u32,
+                (y as f32 * render_config.render_scale) as u32,
+            );
+            if pixel.0 < render_extent.width && pixel.1 < render_extent.height {
+                probe.cmd_copy(
+                    &render_device,
                     cmd_buffer,
-                    &sbt.raygen_region,
-                    &sbt.miss_region,
-                    &sbt.hit_region,
-                    &vk::StridedDeviceAddressRegionKHR::default(),
-                    swapchain.swapchain_extent.width,
-                    swapchain.swapchain_extent.height,
-                    1,
+                    frame.render_frame_buffers.main.0,
+                    pixel,
                 );
             }
         }
 
+        let render_target_capture = crate::screenshot::cmd_capture_render_target(
+            &render_device,
+            cmd_buffer,
+            frame.render_frame_buffers.main.0,
+            render_extent,
+            render_config
+                .physical_camera
+                .map_or(render_config.exposure, |camera| camera.exposure()),
+            screenshots.as_deref_mut(),
+        );
+
         // Make swapchain available for rendering
         vk_utils::transition_image_layout(
             &render_device,
@@ -676,140 +1538,221 @@ fn render_frame(
             vk::ImageLayout::ATTACHMENT_OPTIMAL,
         );
 
-        let render_area = vk::Rect2D::default().extent(swapchain.swapchain_extent);
+        if let Some(statistics) = pipeline_statistics.as_ref() {
+            statistics.cmd_begin(&render_device, cmd_buffer, POST_PROCESS_QUERY);
+        }
 
-        let attachment_info = vk::RenderingAttachmentInfo::default()
-            .image_view(swapchain_view)
-            .image_layout(vk::ImageLayout::ATTACHMENT_OPTIMAL)
-            .load_op(vk::AttachmentLoadOp::CLEAR)
-            .store_op(vk::AttachmentStoreOp::STORE);
+        let descriptor_set_idx = swapchain.frame_count % 2;
+        let postprocess_filter = postprocess_filters.get(&render_config.postprocess_pipeline);
+        let upscale_filter = postprocess_filters
+            .get(&render_config.upscale_pipeline)
+            .filter(|_| {
+                render_config.upscale && render_extent.width < swapchain.swapchain_extent.width
+            });
 
-        let render_info = vk::RenderingInfo::default()
-            .layer_count(1)
-            .render_area(render_area)
-            .color_attachments(std::slice::from_ref(&attachment_info));
+        // the dev ui preview shows the post processed image at the render resolution
+        let preview = dev_ui_stuff
+            .as_ref()
+            .is_some_and(|(_, dev_ui_state, _, _)| {
+                dev_ui_state.preview_open
+                    && screenshots
+                        .as_ref()
+                        .map_or(true, |s| s.screenshots.is_empty())
+            });
 
-        render_device.cmd_begin_rendering(cmd_buffer, &render_info);
+        let post_processed = frame.render_frame_buffers.post_processed;
+        if upscale_filter.is_some() || preview {
+            // post process at the render resolution, the upscaler fills the swapchain from that.
+            vk_utils::transition_image_layout(
+                &render_device,
+                cmd_buffer,
+                post_processed.0,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::ATTACHMENT_OPTIMAL,
+            );
 
-        render_device.cmd_set_scissor(cmd_buffer, 0, std::slice::from_ref(&render_area));
-        render_device.cmd_set_viewport(
-            cmd_buffer,
-            0,
-            std::slice::from_ref(
-                &vk::Viewport::default()
-                    .width(swapchain.swapchain_extent.width as f32)
-                    .height(swapchain.swapchain_extent.height as f32)
-                    .min_depth(0.0)
-                    .max_depth(1.0),
-            ),
-        );
+            begin_rendering(&render_device, cmd_buffer, post_processed.1, render_extent);
+            if let Some(filter) = postprocess_filter {
+                draw_filter(
+                    &render_device,
+                    cmd_buffer,
+                    filter,
+                    filter.descriptor_sets[descriptor_set_idx],
+                    frame.render_frame_buffers.main.1,
+                    vk::ImageLayout::GENERAL,
+                    frame.uniform_buffer.address,
+                );
+            }
+            render_device.cmd_end_rendering(cmd_buffer);
 
-        if let Some(pipeline) = postprocess_filters.get(&render_config.postprocess_pipeline) {
-            render_device.cmd_bind_pipeline(
+            vk_utils::transition_image_layout(
+                &render_device,
                 cmd_buffer,
-                vk::PipelineBindPoint::GRAPHICS,
-                pipeline.pipeline,
+                post_processed.0,
+                vk::ImageLayout::ATTACHMENT_OPTIMAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
             );
+        }
 
-            let push_constants = frame.uniform_buffer.address;
-            render_device.cmd_push_constants(
+        if let Some(upscale_filter) = upscale_filter {
+            begin_rendering(
+                &render_device,
                 cmd_buffer,
-                pipeline.pipeline_layout,
-                vk::ShaderStageFlags::ALL,
-                0,
-                bytemuck::cast_slice(&[push_constants]),
+                swapchain_view,
+                swapchain.swapchain_extent,
             );
+            draw_filter(
+                &render_device,
+                cmd_buffer,
+                upscale_filter,
+                upscale_filter.descriptor_sets[descriptor_set_idx],
+                post_processed.1,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                frame.uniform_buffer.address,
+            );
+        } else {
+            begin_rendering(
+                &render_device,
+                cmd_buffer,
+                swapchain_view,
+                swapchain.swapchain_extent,
+            );
+            if let Some(filter) = postprocess_filter {
+                draw_filter(
+                    &render_device,
+                    cmd_buffer,
+                    filter,
+                    filter.descriptor_sets[2 + descriptor_set_idx],
+                    frame.render_frame_buffers.main.1,
+                    vk::ImageLayout::GENERAL,
+                    frame.uniform_buffer.address,
+                );
+            }
+        }
 
-            // Ensure the descriptor set is up to date
-            let render_target_main_binding = vk::DescriptorImageInfo::default()
-                .image_layout(vk::ImageLayout::GENERAL)
-                .image_view(frame.render_frame_buffers.main.1)
-                .sampler(render_device.linear_sampler);
-
-            let writes = [vk::WriteDescriptorSet::default()
-                .dst_set(pipeline.descriptor_sets[swapchain.frame_count % 2])
-                .dst_binding(0)
-                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-                .image_info(std::slice::from_ref(&render_target_main_binding))];
+        // screenshots are taken without the dev ui
+        if let Some((dev_ui, dev_ui_state, dev_ui_update, dev_ui_platform_output)) =
+            dev_ui_stuff.as_mut().filter(|_| {
+                screenshots
+                    .as_ref()
+                    .map_or(true, |s| s.screenshots.is_empty())
+            })
+        {
+            // render the egui dev ui, it edits a copy of the config which is sent
+            // back to the main app (as an edit, see `RenderConfig::apply_edit`) when changed.
+            let raw_input = dev_ui_update.raw_input.clone();
+            let mut edited_config = render_config.clone();
+            dev_ui_state.render_target = preview.then(|| {
+                dev_ui.render_target_texture(
+                    &render_device,
+                    descriptor_set_idx,
+                    post_processed.1,
+                    render_extent,
+                )
+            });
 
-            render_device.update_descriptor_sets(&writes, &[]);
+            let egui::FullOutput {
+                platform_output,
+                textures_delta,
+                shapes,
+                pixels_per_point,
+                ..
+            } = dev_ui.egui_ctx.run(raw_input, |ctx| {
+                dev_ui_state.ticks = *tick as usize;
+                // no idea why the running average starts at inf.
+                if *fps_runnig_avg > 100000.0 {
+                    *fps_runnig_avg = 0.0;
+                }
+                *fps_runnig_avg = 0.95 * *fps_runnig_avg + 0.05 * (1.0 / time.delta_secs());
+                dev_ui_state.fps = *fps_runnig_avg;
+                dev_ui_state.pipeline_statistics = pipeline_statistics.as_ref().map(|statistics| {
+                    (
+                        statistics.ray_counters,
+                        statistics.trace_time,
+                        statistics.trace,
+                        statistics.post_process,
+                    )
+                });
+                dev_ui_state.trace_time_per_pixel_order = pipeline_statistics
+                    .as_ref()
+                    .map_or([None; 3], |statistics| {
+                        statistics.trace_time_per_pixel_order
+                    });
+                dev_ui_state.pending_assets = asset_progress
+                    .as_ref()
+                    .map_or(0, |progress| progress.pending());
+                dev_ui_state.probed_pixel = pixel_probe.as_ref().and_then(|probe| probe.result);
+                dev_ui_state.render(ctx, &mut edited_config);
+            });
 
-            render_device.cmd_bind_descriptor_sets(
-                cmd_buffer,
-                vk::PipelineBindPoint::GRAPHICS,
-                pipeline.pipeline_layout,
-                0,
-                std::slice::from_ref(&pipeline.descriptor_sets[swapchain.frame_count % 2]),
-                &[],
-            );
+            // send the platform output to the main app for processing
+            {
+                let mut platform_output_slot =
+                    dev_ui_platform_output.platform_output.lock().unwrap();
+                *platform_output_slot = Some(platform_output);
+            }
 
-            render_device.cmd_draw(cmd_buffer, 3, 1, 0, 0);
-        }
+            if edited_config != *render_config {
+                let mut render_config_edits = dev_ui_platform_output.render_config.lock().unwrap();
+                render_config_edits.push((render_config.clone(), edited_config));
+            }
 
-        // render the egui dev ui
-        let raw_input = dev_ui_update.raw_input.clone();
-
-        let egui::FullOutput {
-            platform_output,
-            textures_delta,
-            shapes,
-            pixels_per_point,
-            ..
-        } = dev_ui.egui_ctx.run(raw_input, |ctx| {
-            dev_ui_state.ticks = *tick as usize;
-            // no idea why the running average starts at inf.
-            if *fps_runnig_avg > 100000.0 {
-                *fps_runnig_avg = 0.0;
+            dev_ui.renderer.free_textures(&textures_delta.free).unwrap();
+            if !textures_delta.set.is_empty() {
+                let queue = render_device.queue.lock().unwrap();
+                dev_ui
+                    .renderer
+                    .set_textures(
+                        *queue,
+                        render_device.command_pool,
+                        textures_delta.set.as_slice(),
+                    )
+                    .expect("Failed to update texture");
             }
-            *fps_runnig_avg = 0.95 * *fps_runnig_avg + 0.05 * (1.0 / time.delta_secs());
-            dev_ui_state.fps = *fps_runnig_avg;
-            dev_ui_state.render(ctx);
-        });
 
-        // send the platform output to the main app for processing
-        {
-            let mut platform_output_slot = dev_ui_platform_output.platform_output.lock().unwrap();
-            *platform_output_slot = Some(platform_output);
-        }
+            let clipped_primitives = dev_ui.egui_ctx.tessellate(shapes, pixels_per_point);
 
-        dev_ui.renderer.free_textures(&textures_delta.free).unwrap();
-        if !textures_delta.set.is_empty() {
-            let queue = render_device.queue.lock().unwrap();
             dev_ui
                 .renderer
-                .set_textures(
-                    *queue,
-                    render_device.command_pool,
-                    textures_delta.set.as_slice(),
+                .cmd_draw(
+                    cmd_buffer,
+                    swapchain.swapchain_extent,
+                    pixels_per_point,
+                    &clipped_primitives,
                 )
-                .expect("Failed to update texture");
+                .unwrap();
         }
 
-        let clipped_primitives = dev_ui.egui_ctx.tessellate(shapes, pixels_per_point);
-
-        dev_ui
-            .renderer
-            .cmd_draw(
-                cmd_buffer,
-                swapchain.swapchain_extent,
-                pixels_per_point,
-                &clipped_primitives,
-            )
-            .unwrap();
-
         render_device.cmd_end_rendering(cmd_buffer);
 
+        if let Some(statistics) = pipeline_statistics.as_ref() {
+            statistics.cmd_end(&render_device, cmd_buffer, POST_PROCESS_QUERY);
+        }
+
         // Make swapchain available for present
-        vk_utils::transition_image_layout(
+        let screenshot = crate::screenshot::cmd_present_and_capture(
             &render_device,
             cmd_buffer,
             frame.swapchain_image,
-            vk::ImageLayout::ATTACHMENT_OPTIMAL,
-            vk::ImageLayout::PRESENT_SRC_KHR,
+            swapchain.swapchain_extent,
+            swapchain.surface_format,
+            screenshots.as_deref_mut(),
         );
 
         render_device.end_command_buffer(cmd_buffer).unwrap();
         swapchain.submit_presentation(&window, cmd_buffer);
+
+        if screenshot.is_some() || render_target_capture.is_some() {
+            {
+                let queue = render_device.queue.lock().unwrap();
+                render_device.queue_wait_idle(*queue).unwrap();
+            }
+            // readbacks are only recorded with the screenshot plugin
+            let pending = screenshots.as_deref_mut().unwrap();
+            for readback in [screenshot, render_target_capture].into_iter().flatten() {
+                readback.save(&render_device, pending);
+            }
+        }
     }
 }
 
@@ -844,3 +1787,25 @@ fn on_shutdown(world: &mut World) {
 fn run_if_render_device_exists(device: Option<Res<RenderDevice>>) -> bool {
     device.is_some()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_edit_keeps_fields_the_ui_did_not_change() {
+        let seen_by_ui = RenderConfig::default();
+        let mut edited = seen_by_ui.clone();
+        edited.exposure = 2.0;
+
+        // changed by the main app while the ui was rendering the old copy
+        let mut config = seen_by_ui.clone();
+        config.accumulate = true;
+        config.max_samples = Some(16);
+
+        config.apply_edit(&seen_by_ui, &edited);
+        assert_eq!(config.exposure, 2.0);
+        assert!(config.accumulate);
+        assert_eq!(config.max_samples, Some(16));
+    }
+}
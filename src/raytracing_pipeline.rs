@@ -15,7 +15,6 @@ use bytemuck::{Pod, Zeroable};
 use crate::{
     ray_render_plugin::MainWorld,
     shader::Shader,
-    vk_utils,
     vulkan_asset::{VulkanAsset, VulkanAssetExt},
 };
 
@@ -31,6 +30,14 @@ pub struct RaytracingPipeline {
     pub sphere_intersection_shader: Handle<Shader>,
     #[dependency]
     pub sphere_hit_shader: Handle<Shader>,
+    /// Shared by the triangle and sphere hit groups, only invoked for
+    /// non-opaque geometry or rays traced with [`crate::ray_render_plugin::RayFlags::NO_OPAQUE`].
+    #[dependency]
+    pub any_hit_shader: Handle<Shader>,
+    /// Closest hit shader for the geometries of gltf models with a transmissive
+    /// material (glass), they use `hit_shader` when this is not set.
+    #[dependency]
+    pub transmissive_hit_shader: Option<Handle<Shader>>,
 }
 
 pub type RTGroupHandle = [u8; 32];
@@ -44,6 +51,8 @@ pub struct CompiledRaytracingPipeline {
     pub miss_handle: RTGroupHandle,
     pub hit_handle: RTGroupHandle,
     pub sphere_hit_handle: RTGroupHandle,
+    /// The same as `hit_handle` without a transmissive hit shader.
+    pub transmissive_hit_handle: RTGroupHandle,
 }
 
 #[repr(C)]
@@ -54,11 +63,26 @@ pub struct RaytracingPushConstants {
     pub bluenoise_buffer2: u64,
     pub focus_buffer: u64,
     pub sky_texture: u32,
+    pub count_rays: u32,
+    pub instance_data_buffer: u64,
+    pub time: f32,
+    pub time_sample: u32,
+    pub pixel_order: u32,
     pub padding: [u32; 1],
+    pub ray_counters: u64,
+    pub acceleration_structure: u64,
 }
 
 impl VulkanAsset for RaytracingPipeline {
-    type ExtractedAsset = (Shader, Shader, Shader, Shader, Shader);
+    type ExtractedAsset = (
+        Shader,
+        Shader,
+        Shader,
+        Shader,
+        Shader,
+        Shader,
+        Option<Shader>,
+    );
     type ExtractParam = SRes<MainWorld>;
     type PreparedAsset = CompiledRaytracingPipeline;
 
@@ -96,12 +120,30 @@ impl VulkanAsset for RaytracingPipeline {
             return None;
         };
 
+        let Some(any_hit_shader) = shaders.get(&self.any_hit_shader) else {
+            log::warn!("Any hit shader not ready yet");
+            return None;
+        };
+
+        let transmissive_hit_shader = match &self.transmissive_hit_shader {
+            None => None,
+            Some(handle) => {
+                let Some(shader) = shaders.get(handle) else {
+                    log::warn!("Transmissive hit shader not ready yet");
+                    return None;
+                };
+                Some(shader.clone())
+            }
+        };
+
         Some((
             raygen_shader.clone(),
             miss_shader.clone(),
             hit_shader.clone(),
             sphere_intersection_shader.clone(),
             sphere_hit_shader.clone(),
+            any_hit_shader.clone(),
+            transmissive_hit_shader,
         ))
     }
 
@@ -110,21 +152,21 @@ impl VulkanAsset for RaytracingPipeline {
         render_device: &crate::render_device::RenderDevice,
     ) -> Self::PreparedAsset {
         let start = Instant::now();
-        let (raygen_shader, miss_shader, hit_shader, sphere_intersection_shader, sphere_hit_shader) =
-            asset;
-
-        let bindings = [
-            vk::DescriptorSetLayoutBinding::default()
-                .binding(0)
-                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
-                .descriptor_count(1)
-                .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR),
-            vk::DescriptorSetLayoutBinding::default()
-                .binding(100)
-                .descriptor_type(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR)
-                .descriptor_count(1)
-                .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR),
-        ];
+        let (
+            raygen_shader,
+            miss_shader,
+            hit_shader,
+            sphere_intersection_shader,
+            sphere_hit_shader,
+            any_hit_shader,
+            transmissive_hit_shader,
+        ) = asset;
+
+        let bindings = [vk::DescriptorSetLayoutBinding::default()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)];
 
         let descriptor_set_layout_info =
             vk::DescriptorSetLayoutCreateInfo::default().bindings(&bindings);
@@ -169,7 +211,7 @@ impl VulkanAsset for RaytracingPipeline {
             }
         };
 
-        let shader_stages = [
+        let mut shader_stages = vec![
             render_device.load_shader(
                 &raygen_shader.spirv.unwrap(),
                 vk::ShaderStageFlags::RAYGEN_KHR,
@@ -187,9 +229,19 @@ impl VulkanAsset for RaytracingPipeline {
                 &sphere_hit_shader.spirv.unwrap(),
                 vk::ShaderStageFlags::CLOSEST_HIT_KHR,
             ),
+            render_device.load_shader(
+                &any_hit_shader.spirv.unwrap(),
+                vk::ShaderStageFlags::ANY_HIT_KHR,
+            ),
         ];
+        if let Some(transmissive_hit_shader) = &transmissive_hit_shader {
+            shader_stages.push(render_device.load_shader(
+                transmissive_hit_shader.spirv.as_ref().unwrap(),
+                vk::ShaderStageFlags::CLOSEST_HIT_KHR,
+            ));
+        }
 
-        let shader_group = [
+        let mut shader_group = vec![
             // Raygen shader
             vk::RayTracingShaderGroupCreateInfoKHR::default()
                 .ty(vk::RayTracingShaderGroupTypeKHR::GENERAL)
@@ -209,16 +261,27 @@ impl VulkanAsset for RaytracingPipeline {
                 .ty(vk::RayTracingShaderGroupTypeKHR::TRIANGLES_HIT_GROUP)
                 .general_shader(vk::SHADER_UNUSED_KHR)
                 .closest_hit_shader(2)
-                .any_hit_shader(vk::SHADER_UNUSED_KHR)
+                .any_hit_shader(5)
                 .intersection_shader(vk::SHADER_UNUSED_KHR),
             // Sphere shader
             vk::RayTracingShaderGroupCreateInfoKHR::default()
                 .ty(vk::RayTracingShaderGroupTypeKHR::PROCEDURAL_HIT_GROUP)
                 .general_shader(vk::SHADER_UNUSED_KHR)
                 .closest_hit_shader(4)
-                .any_hit_shader(vk::SHADER_UNUSED_KHR)
+                .any_hit_shader(5)
                 .intersection_shader(3),
         ];
+        if transmissive_hit_shader.is_some() {
+            // Transmissive triangle hit shader
+            shader_group.push(
+                vk::RayTracingShaderGroupCreateInfoKHR::default()
+                    .ty(vk::RayTracingShaderGroupTypeKHR::TRIANGLES_HIT_GROUP)
+                    .general_shader(vk::SHADER_UNUSED_KHR)
+                    .closest_hit_shader(6)
+                    .any_hit_shader(5)
+                    .intersection_shader(vk::SHADER_UNUSED_KHR),
+            );
+        }
 
         let pipeline_info = vk::RayTracingPipelineCreateInfoKHR::default()
             .stages(&shader_stages)
@@ -244,14 +307,14 @@ impl VulkanAsset for RaytracingPipeline {
             }
         }
 
-        let rtprops = vk_utils::get_raytracing_properties(&render_device);
+        let rtprops = render_device.ray_tracing_properties();
         let handle_size = rtprops.shader_group_handle_size;
         assert!(
             handle_size as usize == std::mem::size_of::<RTGroupHandle>(),
             "at the time we only support 128-bit handles (at time of writing all devices have this)"
         );
 
-        let handle_count = 4;
+        let handle_count = shader_group.len() as u32;
         let handle_data_size = handle_count * handle_size;
         let handles: Vec<RTGroupHandle> = unsafe {
             render_device
@@ -276,6 +339,7 @@ impl VulkanAsset for RaytracingPipeline {
         let miss_handle = handles[1];
         let hit_handle = handles[2];
         let sphere_hit_handle = handles[3];
+        let transmissive_hit_handle = handles.get(4).copied().unwrap_or(hit_handle);
 
         log::info!("Raytracing pipeline compiled in {:?}", start.elapsed());
 
@@ -288,6 +352,7 @@ impl VulkanAsset for RaytracingPipeline {
             miss_handle,
             hit_handle,
             sphere_hit_handle,
+            transmissive_hit_handle,
         }
     }
 
@@ -321,6 +386,11 @@ fn propagate_modified(
                         || filter.hit_shader.id() == *id
                         || filter.sphere_intersection_shader.id() == *id
                         || filter.sphere_hit_shader.id() == *id
+                        || filter.any_hit_shader.id() == *id
+                        || filter
+                            .transmissive_hit_shader
+                            .as_ref()
+                            .is_some_and(|shader| shader.id() == *id)
                     {
                         parent_events.send(AssetEvent::Modified {
                             id: parent_id.clone(),
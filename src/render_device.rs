@@ -2,15 +2,18 @@ use std::{
     collections::VecDeque,
     ffi::{c_char, CStr},
     mem::ManuallyDrop,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
 };
 
 use ash::vk;
 use ash::{
-    ext::descriptor_indexing,
+    ext::{descriptor_indexing, full_screen_exclusive},
     khr::{
-        acceleration_structure, deferred_host_operations, maintenance4, ray_tracing_pipeline,
-        spirv_1_4, surface, swapchain, synchronization2,
+        acceleration_structure, deferred_host_operations, get_surface_capabilities2, maintenance4,
+        ray_tracing_pipeline, spirv_1_4, surface, swapchain, synchronization2,
     },
 };
 use bevy::{prelude::*, utils::HashMap};
@@ -22,6 +25,36 @@ use crate::render_texture::RenderTexture;
 
 const MAX_BINDLESS_IMAGES: u32 = 16536;
 
+/// Ray tracing pipeline limits of the physical device.
+#[derive(Clone, Copy, Debug)]
+pub struct RayTracingProperties {
+    pub max_ray_recursion_depth: u32,
+    pub shader_group_handle_size: u32,
+    pub shader_group_handle_alignment: u32,
+    pub shader_group_base_alignment: u32,
+    pub max_shader_group_stride: u32,
+    pub max_ray_dispatch_invocation_count: u32,
+    pub max_ray_hit_attribute_size: u32,
+}
+
+/// Acceleration structure limits of the physical device.
+#[derive(Clone, Copy, Debug)]
+pub struct AccelerationStructureProperties {
+    pub max_geometry_count: u64,
+    pub max_instance_count: u64,
+    pub max_primitive_count: u64,
+    pub max_descriptor_set_acceleration_structures: u32,
+    pub min_acceleration_structure_scratch_offset_alignment: u32,
+}
+
+/// The device limits, available in the main world for apps that want to adapt to
+/// (or display) them. The render world can ask the [`RenderDevice`] directly.
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct DeviceProperties {
+    pub ray_tracing: RayTracingProperties,
+    pub acceleration_structure: AccelerationStructureProperties,
+}
+
 pub struct AllocatorState {
     allocator: Arc<Mutex<Allocator>>,
     image_allocations: HashMap<vk::Image, Allocation>,
@@ -91,10 +124,18 @@ pub struct RenderDeviceData {
     pub ext_sync2: synchronization2::Device,
     pub ext_rtx_pipeline: ray_tracing_pipeline::Device,
     pub ext_acc_struct: acceleration_structure::Device,
+    /// Only available on windows drivers that support exclusive fullscreen.
+    pub ext_full_screen_exclusive: Option<full_screen_exclusive::Device>,
+    pub supports_pipeline_statistics: bool,
+    properties: DeviceProperties,
     pub command_pool: vk::CommandPool,
     pub bindless_descriptor_set: vk::DescriptorSet,
     pub bindless_descriptor_set_layout: vk::DescriptorSetLayout,
     pub bindless_descriptor_map: Mutex<HashMap<vk::ImageView, u32>>,
+    /// Number of descriptors allocated for the bindless textures, registering more
+    /// textures than this falls back to the white texture.
+    pub bindless_capacity: u32,
+    bindless_overflow_reported: AtomicBool,
     pub transfer_command_pool: Mutex<vk::CommandPool>,
     pub command_buffers: [vk::CommandBuffer; 2],
     pub descriptor_pool: Mutex<vk::DescriptorPool>,
@@ -123,19 +164,34 @@ impl Clone for RenderDevice {
 impl RenderDevice {
     pub unsafe fn from_display(display_handle: &DisplayHandle) -> Self {
         let entry = ash::Entry::linked();
-        let instance = create_instance(display_handle, &entry);
+        let (instance, has_surface_capabilities2) = create_instance(display_handle, &entry);
         let ext_surface = surface::Instance::new(&entry, &instance);
         let (physical_device, queue_family_idx) = pick_physical_device(&instance);
-        let (device, queue) = create_logical_device(&instance, physical_device, queue_family_idx);
+        let supports_full_screen_exclusive = has_surface_capabilities2
+            && device_supports_extension(&instance, physical_device, full_screen_exclusive::NAME);
+        let supports_pipeline_statistics = instance
+            .get_physical_device_features(physical_device)
+            .pipeline_statistics_query
+            == vk::TRUE;
+        let (device, queue) = create_logical_device(
+            &instance,
+            physical_device,
+            queue_family_idx,
+            supports_full_screen_exclusive,
+            supports_pipeline_statistics,
+        );
         let ext_swapchain = swapchain::Device::new(&instance, &device);
         let ext_sync2 = synchronization2::Device::new(&instance, &device);
         let ext_rtx_pipeline = ray_tracing_pipeline::Device::new(&instance, &device);
         let ext_acc_struct = acceleration_structure::Device::new(&instance, &device);
+        let ext_full_screen_exclusive = supports_full_screen_exclusive
+            .then(|| full_screen_exclusive::Device::new(&instance, &device));
+        let properties = query_device_properties(&instance, physical_device);
         let command_pool = create_command_pool(&device, queue_family_idx);
         let transfer_command_pool = Mutex::new(create_command_pool(&device, queue_family_idx));
         let command_buffers = create_command_buffers(&device, command_pool);
         let descriptor_pool = create_descriptor_pool(&device);
-        let (bindless_descriptor_set, bindless_descriptor_set_layout) =
+        let (bindless_descriptor_set, bindless_descriptor_set_layout, bindless_capacity) =
             create_global_descriptor(device.clone(), *descriptor_pool.lock().unwrap());
         let linear_sampler = create_linear_sampler(device.clone());
 
@@ -170,10 +226,15 @@ impl RenderDevice {
             ext_sync2,
             ext_rtx_pipeline,
             ext_acc_struct,
+            ext_full_screen_exclusive,
+            supports_pipeline_statistics,
+            properties,
             command_pool,
             bindless_descriptor_set,
             bindless_descriptor_set_layout,
             bindless_descriptor_map: Mutex::new(HashMap::new()),
+            bindless_capacity,
+            bindless_overflow_reported: AtomicBool::new(false),
             transfer_command_pool,
             command_buffers,
             descriptor_pool,
@@ -185,6 +246,18 @@ impl RenderDevice {
         ret
     }
 
+    pub fn ray_tracing_properties(&self) -> RayTracingProperties {
+        self.properties.ray_tracing
+    }
+
+    pub fn acceleration_structure_properties(&self) -> AccelerationStructureProperties {
+        self.properties.acceleration_structure
+    }
+
+    pub fn properties(&self) -> DeviceProperties {
+        self.properties
+    }
+
     pub fn create_render_target(&self, image_info: &vk::ImageCreateInfo) -> vk::Image {
         let image = unsafe { self.device.create_image(image_info, None).unwrap() };
         let requirements = unsafe { self.device.get_image_memory_requirements(image) };
@@ -210,12 +283,43 @@ impl RenderDevice {
         image
     }
 
-    pub fn register_bindless_texture(&self, texture: &RenderTexture) -> u32 {
+    /// Whether another texture fits in the bindless array, so that callers can skip
+    /// uploading a texture that would not be used. Logs an error the first time it
+    /// is full.
+    pub fn has_bindless_capacity(&self) -> bool {
+        let map = self.bindless_descriptor_map.lock().unwrap();
+        self.check_bindless_capacity(map.len())
+    }
+
+    fn check_bindless_capacity(&self, len: usize) -> bool {
+        if len < self.bindless_capacity as usize {
+            return true;
+        }
+
+        if !self
+            .bindless_overflow_reported
+            .swap(true, Ordering::Relaxed)
+        {
+            log::error!(
+                "Bindless texture array is full ({} textures), further textures are replaced by their fallback",
+                self.bindless_capacity
+            );
+        }
+        false
+    }
+
+    /// Returns the index of the texture in the bindless array, or `fallback` if the
+    /// array is full. The fallback should stand in for the texture, like
+    /// `DEFAULT_NORMAL_TEXTURE_IDX` for a normal map.
+    pub fn register_bindless_texture(&self, texture: &RenderTexture, fallback: u32) -> u32 {
         let mut map = self.bindless_descriptor_map.lock().unwrap();
         if let Some(index) = map.get(&texture.image_view) {
             return *index;
         }
 
+        if !self.check_bindless_capacity(map.len()) {
+            return fallback;
+        }
         let index = map.len() as u32;
         map.insert(texture.image_view, index);
 
@@ -264,34 +368,47 @@ impl RenderDevice {
     }
 
     pub fn run_transfer_commands(&self, f: impl FnOnce(vk::CommandBuffer)) {
-        let queue = self.queue.lock().unwrap();
-        let transfer_command_pool = self.transfer_command_pool.lock().unwrap();
         let fence_info = vk::FenceCreateInfo::default();
         let fence = unsafe { self.device.create_fence(&fence_info, None) }.unwrap();
-        let alloc_info = vk::CommandBufferAllocateInfo::default()
-            .command_pool(*transfer_command_pool)
-            .level(vk::CommandBufferLevel::PRIMARY)
-            .command_buffer_count(1);
-        let cmd_buffer = unsafe { self.device.allocate_command_buffers(&alloc_info) }.unwrap()[0];
-        let begin_info = vk::CommandBufferBeginInfo::default()
-            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
-        unsafe { self.device.begin_command_buffer(cmd_buffer, &begin_info) }.unwrap();
-
-        f(cmd_buffer);
 
-        unsafe { self.device.end_command_buffer(cmd_buffer) }.unwrap();
+        // recording requires the pool to be externally synchronized
+        let cmd_buffer = {
+            let transfer_command_pool = self.transfer_command_pool.lock().unwrap();
+            let alloc_info = vk::CommandBufferAllocateInfo::default()
+                .command_pool(*transfer_command_pool)
+                .level(vk::CommandBufferLevel::PRIMARY)
+                .command_buffer_count(1);
+            let cmd_buffer =
+                unsafe { self.device.allocate_command_buffers(&alloc_info) }.unwrap()[0];
+            let begin_info = vk::CommandBufferBeginInfo::default()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+            unsafe { self.device.begin_command_buffer(cmd_buffer, &begin_info) }.unwrap();
+
+            f(cmd_buffer);
+
+            unsafe { self.device.end_command_buffer(cmd_buffer) }.unwrap();
+            cmd_buffer
+        };
 
-        unsafe { self.device.reset_fences(std::slice::from_ref(&fence)) }.unwrap();
         let submit_info =
             vk::SubmitInfo::default().command_buffers(std::slice::from_ref(&cmd_buffer));
 
+        // Only hold the queue for the submit itself, the render thread needs it
+        // to submit and present frames while a (potentially long) upload runs.
+        {
+            let queue = self.queue.lock().unwrap();
+            unsafe {
+                self.device
+                    .queue_submit(*queue, std::slice::from_ref(&submit_info), fence)
+                    .unwrap();
+            }
+        }
+
         unsafe {
-            self.device
-                .queue_submit(*queue, std::slice::from_ref(&submit_info), fence)
-                .unwrap();
             self.device
                 .wait_for_fences(std::slice::from_ref(&fence), true, u64::MAX)
                 .unwrap();
+            let transfer_command_pool = self.transfer_command_pool.lock().unwrap();
             self.device
                 .free_command_buffers(*transfer_command_pool, std::slice::from_ref(&cmd_buffer));
             self.device.destroy_fence(fence, None);
@@ -329,7 +446,12 @@ impl Drop for RenderDeviceData {
     }
 }
 
-unsafe fn create_instance(display_handle: &DisplayHandle, entry: &ash::Entry) -> ash::Instance {
+/// Also returns whether VK_KHR_get_surface_capabilities2 was enabled, which
+/// VK_EXT_full_screen_exclusive depends on.
+unsafe fn create_instance(
+    display_handle: &DisplayHandle,
+    entry: &ash::Entry,
+) -> (ash::Instance, bool) {
     let app_name = CStr::from_bytes_with_nul_unchecked(b"VK RAYS\0");
     let mut layer_names: Vec<&CStr> = Vec::new();
 
@@ -347,8 +469,19 @@ unsafe fn create_instance(display_handle: &DisplayHandle, entry: &ash::Entry) ->
         .iter()
         .map(|raw_name| raw_name.as_ptr())
         .collect();
-    let instance_extensions =
-        ash_window::enumerate_required_extensions(display_handle.as_raw()).unwrap();
+    let mut instance_extensions =
+        ash_window::enumerate_required_extensions(display_handle.as_raw())
+            .unwrap()
+            .to_vec();
+
+    let has_surface_capabilities2 = entry
+        .enumerate_instance_extension_properties(None)
+        .unwrap()
+        .iter()
+        .any(|p| p.extension_name_as_c_str() == Ok(get_surface_capabilities2::NAME));
+    if has_surface_capabilities2 {
+        instance_extensions.push(get_surface_capabilities2::NAME.as_ptr());
+    }
 
     println!("Instance extensions:");
     for extension_name in instance_extensions.iter() {
@@ -367,7 +500,22 @@ unsafe fn create_instance(display_handle: &DisplayHandle, entry: &ash::Entry) ->
         .enabled_layer_names(&layers_names_raw)
         .enabled_extension_names(&instance_extensions);
 
-    entry.create_instance(&instance_info, None).unwrap()
+    (
+        entry.create_instance(&instance_info, None).unwrap(),
+        has_surface_capabilities2,
+    )
+}
+
+unsafe fn device_supports_extension(
+    instance: &ash::Instance,
+    physical_device: vk::PhysicalDevice,
+    name: &CStr,
+) -> bool {
+    instance
+        .enumerate_device_extension_properties(physical_device)
+        .unwrap()
+        .iter()
+        .any(|p| p.extension_name_as_c_str() == Ok(name))
 }
 
 unsafe fn pick_physical_device(instance: &ash::Instance) -> (vk::PhysicalDevice, u32) {
@@ -416,12 +564,48 @@ unsafe fn pick_physical_device(instance: &ash::Instance) -> (vk::PhysicalDevice,
     (physical_device, queue_family_idx)
 }
 
+/// Queried once, the properties of a physical device don't change.
+unsafe fn query_device_properties(
+    instance: &ash::Instance,
+    physical_device: vk::PhysicalDevice,
+) -> DeviceProperties {
+    let mut rt = vk::PhysicalDeviceRayTracingPipelinePropertiesKHR::default();
+    let mut acc = vk::PhysicalDeviceAccelerationStructurePropertiesKHR::default();
+    let mut properties2 = vk::PhysicalDeviceProperties2KHR::default()
+        .push_next(&mut rt)
+        .push_next(&mut acc);
+    instance.get_physical_device_properties2(physical_device, &mut properties2);
+
+    DeviceProperties {
+        ray_tracing: RayTracingProperties {
+            max_ray_recursion_depth: rt.max_ray_recursion_depth,
+            shader_group_handle_size: rt.shader_group_handle_size,
+            shader_group_handle_alignment: rt.shader_group_handle_alignment,
+            shader_group_base_alignment: rt.shader_group_base_alignment,
+            max_shader_group_stride: rt.max_shader_group_stride,
+            max_ray_dispatch_invocation_count: rt.max_ray_dispatch_invocation_count,
+            max_ray_hit_attribute_size: rt.max_ray_hit_attribute_size,
+        },
+        acceleration_structure: AccelerationStructureProperties {
+            max_geometry_count: acc.max_geometry_count,
+            max_instance_count: acc.max_instance_count,
+            max_primitive_count: acc.max_primitive_count,
+            max_descriptor_set_acceleration_structures: acc
+                .max_descriptor_set_acceleration_structures,
+            min_acceleration_structure_scratch_offset_alignment: acc
+                .min_acceleration_structure_scratch_offset_alignment,
+        },
+    }
+}
+
 unsafe fn create_logical_device(
     instance: &ash::Instance,
     physical_device: vk::PhysicalDevice,
     queue_family_idx: u32,
+    enable_full_screen_exclusive: bool,
+    enable_pipeline_statistics: bool,
 ) -> (ash::Device, Mutex<vk::Queue>) {
-    let device_extensions = [
+    let mut device_extensions = vec![
         swapchain::NAME.as_ptr(),
         synchronization2::NAME.as_ptr(),
         maintenance4::NAME.as_ptr(),
@@ -432,6 +616,10 @@ unsafe fn create_logical_device(
         descriptor_indexing::NAME.as_ptr(),
     ];
 
+    if enable_full_screen_exclusive {
+        device_extensions.push(full_screen_exclusive::NAME.as_ptr());
+    }
+
     println!("Device extensions:");
     for extension_name in device_extensions.iter() {
         println!("  - {}", CStr::from_ptr(*extension_name).to_str().unwrap());
@@ -468,9 +656,13 @@ unsafe fn create_logical_device(
     let mut features_scalar_block =
         vk::PhysicalDeviceScalarBlockLayoutFeatures::default().scalar_block_layout(true);
 
+    let features =
+        vk::PhysicalDeviceFeatures::default().pipeline_statistics_query(enable_pipeline_statistics);
+
     let device_info = vk::DeviceCreateInfo::default()
         .queue_create_infos(std::slice::from_ref(&queue_info))
         .enabled_extension_names(&device_extensions)
+        .enabled_features(&features)
         .push_next(&mut sync2_info)
         .push_next(&mut dynamic_rendering_info)
         .push_next(&mut maintaince4_info)
@@ -518,7 +710,29 @@ fn create_descriptor_pool(device: &ash::Device) -> Mutex<vk::DescriptorPool> {
         },
         vk::DescriptorPoolSize {
             ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-            descriptor_count: MAX_BINDLESS_IMAGES,
+            // the bindless array and the post process filters
+            descriptor_count: MAX_BINDLESS_IMAGES + 1000,
+        },
+        // the ray tracing pipelines and compute passes
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::SAMPLER,
+            descriptor_count: 1000,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::SAMPLED_IMAGE,
+            descriptor_count: 1000,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_IMAGE,
+            descriptor_count: 1000,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1000,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::ACCELERATION_STRUCTURE_KHR,
+            descriptor_count: 100,
         },
     ];
 
@@ -537,8 +751,7 @@ fn create_descriptor_pool(device: &ash::Device) -> Mutex<vk::DescriptorPool> {
 fn create_global_descriptor(
     device: ash::Device,
     descriptor_pool: vk::DescriptorPool,
-) -> (vk::DescriptorSet, vk::DescriptorSetLayout) {
-    const MAX_BINDLESS_IMAGES: u32 = 16536;
+) -> (vk::DescriptorSet, vk::DescriptorSetLayout, u32) {
     let image_binding = vk::DescriptorSetLayoutBinding::default()
         .binding(200)
         .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
@@ -548,7 +761,7 @@ fn create_global_descriptor(
     let bindless_flags = vk::DescriptorBindingFlags::VARIABLE_DESCRIPTOR_COUNT
         | vk::DescriptorBindingFlags::PARTIALLY_BOUND
         | vk::DescriptorBindingFlags::UPDATE_AFTER_BIND;
-    let max_binding = MAX_BINDLESS_IMAGES - 1;
+    let capacity = MAX_BINDLESS_IMAGES;
 
     let mut layout_info_ext = vk::DescriptorSetLayoutBindingFlagsCreateInfo::default()
         .binding_flags(std::slice::from_ref(&bindless_flags));
@@ -565,7 +778,7 @@ fn create_global_descriptor(
     };
 
     let mut alloc_info_ext = vk::DescriptorSetVariableDescriptorCountAllocateInfo::default()
-        .descriptor_counts(std::slice::from_ref(&max_binding));
+        .descriptor_counts(std::slice::from_ref(&capacity));
 
     let alloc_info = vk::DescriptorSetAllocateInfo::default()
         .descriptor_pool(descriptor_pool)
@@ -580,7 +793,7 @@ fn create_global_descriptor(
             .unwrap()
     };
 
-    return (descriptor_set, descriptor_set_layout);
+    return (descriptor_set, descriptor_set_layout, capacity);
 }
 
 fn create_linear_sampler(device: ash::Device) -> vk::Sampler {
@@ -1,5 +1,5 @@
 use crate::{
-    ray_render_plugin::TeardownSchedule,
+    ray_render_plugin::RenderAppExt,
     render_device::RenderDevice,
     render_texture::{load_texture_from_bytes, RenderTexture},
 };
@@ -21,32 +21,40 @@ impl Plugin for RenderEnvPlugin {
     fn build(&self, app: &mut App) {
         let render_app = app.get_sub_app_mut(RenderApp).unwrap();
         let device = render_app.world().get_resource::<RenderDevice>().unwrap();
+        // Float textures so the values are exact, 1.0 is 1.0 in linear and sRGB space
+        // and the default normal decodes to exactly (0, 0, 1).
         let white_texture = load_texture_from_bytes(
             device,
-            vk::Format::R8G8B8A8_UNORM,
+            vk::Format::R32G32B32A32_SFLOAT,
             vk::ImageUsageFlags::SAMPLED,
             vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-            &[255, 255, 255, 255],
+            bytemuck::cast_slice(&[1.0f32, 1.0, 1.0, 1.0]),
             1,
             1,
-        );
+            None,
+        )
+        .unwrap();
 
         let default_normal_texture = load_texture_from_bytes(
             device,
-            vk::Format::R8G8B8A8_UNORM,
+            vk::Format::R32G32B32A32_SFLOAT,
             vk::ImageUsageFlags::SAMPLED,
             vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-            &[128, 128, 255, 0],
+            bytemuck::cast_slice(&[0.5f32, 0.5, 1.0, 1.0]),
             1,
             1,
-        );
+            None,
+        )
+        .unwrap();
 
         assert!(
-            device.register_bindless_texture(&white_texture) == WHITE_TEXTURE_IDX,
+            device.register_bindless_texture(&white_texture, WHITE_TEXTURE_IDX)
+                == WHITE_TEXTURE_IDX,
             "default white texture must be index 0"
         );
         assert!(
-            device.register_bindless_texture(&default_normal_texture) == DEFAULT_NORMAL_TEXTURE_IDX,
+            device.register_bindless_texture(&default_normal_texture, WHITE_TEXTURE_IDX)
+                == DEFAULT_NORMAL_TEXTURE_IDX,
             "default normal texture must be index 1"
         );
 
@@ -54,7 +62,7 @@ impl Plugin for RenderEnvPlugin {
             white_texture,
             default_normal_texture,
         });
-        render_app.add_systems(TeardownSchedule, cleanup);
+        app.add_render_teardown(cleanup);
     }
 }
 
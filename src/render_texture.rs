@@ -5,10 +5,12 @@ use bevy::{
     image::{CompressedImageFormats, HdrTextureLoader, ImageLoader},
 };
 use gpu_allocator::vulkan::{AllocationCreateDesc, AllocationScheme};
+use thiserror::Error;
 
 use crate::{
     render_buffer::BufferProvider,
     render_device::RenderDevice,
+    render_env::WHITE_TEXTURE_IDX,
     vk_init, vk_utils,
     vulkan_asset::{VulkanAsset, VulkanAssetExt},
 };
@@ -30,10 +32,25 @@ pub struct RenderTexture {
     pub image_view: vk::ImageView,
 }
 
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum TextureError {
+    #[error("cannot upload an empty {width}x{height} texture")]
+    Empty { width: u32, height: u32 },
+    #[error("row stride of {row_stride} bytes is not valid for {width} pixels of {bytes_per_pixel} bytes")]
+    RowStride {
+        row_stride: u32,
+        width: u32,
+        bytes_per_pixel: u32,
+    },
+    #[error("expected {expected} bytes, got {len}")]
+    Size { expected: usize, len: usize },
+}
+
 impl VulkanAsset for bevy::prelude::Image {
     type ExtractedAsset = bevy::prelude::Image;
     type ExtractParam = ();
-    type PreparedAsset = RenderTexture;
+    /// `None` if the image could not be uploaded.
+    type PreparedAsset = Option<RenderTexture>;
 
     fn extract_asset(
         &self,
@@ -46,9 +63,12 @@ impl VulkanAsset for bevy::prelude::Image {
         asset: Self::ExtractedAsset,
         render_device: &RenderDevice,
     ) -> Self::PreparedAsset {
-        let bytes_per_pixel = asset.data.len()
-            / (asset.texture_descriptor.size.width as usize
-                * asset.texture_descriptor.size.height as usize);
+        let size = asset.texture_descriptor.size;
+        let bytes_per_pixel = asset
+            .texture_descriptor
+            .format
+            .block_copy_size(None)
+            .unwrap_or_default();
 
         let format = match bytes_per_pixel {
             4 => vk::Format::R8G8B8A8_UNORM,
@@ -62,23 +82,35 @@ impl VulkanAsset for bevy::prelude::Image {
             vk::ImageUsageFlags::SAMPLED,
             vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
             asset.data.as_ref(),
-            asset.texture_descriptor.size.width,
-            asset.texture_descriptor.size.height,
+            size.width,
+            size.height,
+            source_row_stride(asset.data.len(), size.height, size.width * bytes_per_pixel),
         );
 
-        render_device.register_bindless_texture(&res);
-
-        res
+        match res {
+            Ok(res) => {
+                render_device.register_bindless_texture(&res, WHITE_TEXTURE_IDX);
+                Some(res)
+            }
+            Err(e) => {
+                log::warn!("Failed to upload image: {}", e);
+                None
+            }
+        }
     }
 
     fn destroy_asset(render_device: &RenderDevice, prepared_asset: &Self::PreparedAsset) {
-        render_device
-            .destroyer
-            .destroy_image_view(prepared_asset.image_view);
-        render_device.destroyer.destroy_image(prepared_asset.image);
+        if let Some(prepared_asset) = prepared_asset {
+            render_device
+                .destroyer
+                .destroy_image_view(prepared_asset.image_view);
+            render_device.destroyer.destroy_image(prepared_asset.image);
+        }
     }
 }
 
+/// Uploads pixel data to a new device local image. `row_stride` is the distance
+/// in bytes between the start of two consecutive rows, `None` means tightly packed.
 pub fn load_texture_from_bytes(
     device: &RenderDevice,
     format: vk::Format,
@@ -87,23 +119,11 @@ pub fn load_texture_from_bytes(
     bytes: &[u8],
     width: u32,
     height: u32,
-) -> RenderTexture {
-    let target_bytes_per_pixel = match format {
-        vk::Format::R8G8B8A8_UNORM => 4,
-        vk::Format::R32G32B32A32_SFLOAT => 16,
-        _ => panic!("unsupported format"),
-    };
-
-    assert!(
-        bytes.len() == (width * height) as usize * target_bytes_per_pixel,
-        "expected {} bytes, got {}",
-        (width * height) as usize * target_bytes_per_pixel,
-        bytes.len()
-    );
-    let mut staging_buffer = device.create_host_buffer::<u8>(
-        (width * height * target_bytes_per_pixel as u32) as u64,
-        vk::BufferUsageFlags::TRANSFER_SRC,
-    );
+    row_stride: Option<u32>,
+) -> Result<RenderTexture, TextureError> {
+    let copy_region = upload_region(format, width, height, row_stride, bytes.len())?;
+    let mut staging_buffer =
+        device.create_host_buffer::<u8>(bytes.len() as u64, vk::BufferUsageFlags::TRANSFER_SRC);
     {
         let mut staging_buffer = device.map_buffer(&mut staging_buffer);
         staging_buffer.as_slice_mut().copy_from_slice(bytes);
@@ -179,7 +199,6 @@ pub fn load_texture_from_bytes(
     });
 
     device.run_transfer_commands(|cmd_buffer| {
-        let copy_region = vk_init::buffer_image_copy(width, height);
         unsafe {
             device.device.cmd_copy_buffer_to_image(
                 cmd_buffer,
@@ -206,32 +225,257 @@ pub fn load_texture_from_bytes(
     let view_info = vk_init::image_view_info(image_handle.clone(), format);
     let view = unsafe { device.device.create_image_view(&view_info, None).unwrap() };
 
-    RenderTexture {
+    Ok(RenderTexture {
         image: image_handle,
         image_view: view,
+    })
+}
+
+/// The copy of `len` bytes of pixel data, with rows `row_stride` bytes apart, to a
+/// `width`x`height` image of `format`.
+fn upload_region(
+    format: vk::Format,
+    width: u32,
+    height: u32,
+    row_stride: Option<u32>,
+    len: usize,
+) -> Result<vk::BufferImageCopy, TextureError> {
+    let bytes_per_pixel = match format {
+        vk::Format::R8G8B8A8_UNORM => 4,
+        vk::Format::R16G16B16A16_UNORM => 8,
+        vk::Format::R32G32B32A32_SFLOAT => 16,
+        _ => panic!("unsupported format"),
+    };
+
+    let row_stride = validate_row_stride(width, height, bytes_per_pixel, row_stride, len)?;
+    // the row length of the buffer is in texels
+    Ok(vk_init::buffer_image_copy(
+        width,
+        height,
+        row_stride / bytes_per_pixel,
+    ))
+}
+
+/// Checks that `len` bytes hold `height` rows of `width` pixels that are `row_stride`
+/// bytes apart and returns the row stride in bytes, tightly packed if `None`.
+fn validate_row_stride(
+    width: u32,
+    height: u32,
+    bytes_per_pixel: u32,
+    row_stride: Option<u32>,
+    len: usize,
+) -> Result<u32, TextureError> {
+    if width == 0 || height == 0 {
+        return Err(TextureError::Empty { width, height });
+    }
+
+    let packed_row_size = width * bytes_per_pixel;
+    let row_stride = row_stride.unwrap_or(packed_row_size);
+    if row_stride < packed_row_size || row_stride % bytes_per_pixel != 0 {
+        return Err(TextureError::RowStride {
+            row_stride,
+            width,
+            bytes_per_pixel,
+        });
+    }
+
+    // the padding after the last row is allowed to be missing
+    let min_size = (row_stride * (height - 1) + packed_row_size) as usize;
+    if len < min_size || len > (row_stride * height) as usize {
+        return Err(TextureError::Size {
+            expected: (row_stride * height) as usize,
+            len,
+        });
+    }
+
+    Ok(row_stride)
+}
+
+/// The row stride of `len` bytes holding `height` rows that take `packed_row_size`
+/// bytes without padding, `None` if there is no padding.
+pub fn source_row_stride(len: usize, height: u32, packed_row_size: u32) -> Option<u32> {
+    let height = height as usize;
+    if height == 0 || len % height != 0 {
+        return None;
     }
+    let row_stride = (len / height) as u32;
+    (row_stride > packed_row_size).then_some(row_stride)
 }
 
-pub fn padd_pixel_bytes_rgba_unorm(
+/// Drops the padding at the end of every row, for pixel data that is converted
+/// before the upload.
+pub fn strip_row_padding(bytes: &[u8], row_stride: usize, packed_row_size: usize) -> Vec<u8> {
+    bytes
+        .chunks(row_stride)
+        .flat_map(|row| &row[..packed_row_size])
+        .copied()
+        .collect()
+}
+
+/// Pads pixels with fewer than 4 channels to rgba. `color` and `alpha` hold the
+/// bytes of the values of the missing color channels and of a missing alpha channel,
+/// they determine the size of a channel.
+pub fn padd_pixel_bytes_rgba(
     bytes: &[u8],
-    src_bytes_per_pixel: u32,
+    src_channels: usize,
+    color: &[u8],
+    alpha: &[u8],
     width: usize,
     height: usize,
 ) -> Vec<u8> {
-    let mut padded_bytes = vec![0u8; (width * height * 4) as usize];
-
-    for pixel_idx in 0..width * height {
-        for channel_idx in 0..4 {
-            if channel_idx < src_bytes_per_pixel {
-                padded_bytes[pixel_idx * 4 + channel_idx as usize] =
-                    bytes[pixel_idx * src_bytes_per_pixel as usize + channel_idx as usize];
-            } else {
-                // padd alpha white, color black
-                padded_bytes[pixel_idx * 4 + channel_idx as usize] =
-                    if channel_idx == 3 { 255 } else { 0 };
-            }
+    assert_eq!(color.len(), alpha.len(), "channels differ in size");
+    let channel_size = alpha.len();
+    let src_pixel_size = src_channels * channel_size;
+    let dst_pixel_size = 4 * channel_size;
+    let mut padded_bytes = vec![0u8; width * height * dst_pixel_size];
+
+    for (src, dst) in bytes
+        .chunks_exact(src_pixel_size)
+        .zip(padded_bytes.chunks_exact_mut(dst_pixel_size))
+    {
+        dst[..src_pixel_size].copy_from_slice(src);
+        for channel in src_channels.min(3)..3 {
+            dst[channel * channel_size..(channel + 1) * channel_size].copy_from_slice(color);
+        }
+        if src_channels < 4 {
+            dst[3 * channel_size..].copy_from_slice(alpha);
         }
     }
 
     padded_bytes
 }
+
+/// Widens grayscale pixels (luminance, optionally followed by alpha) to rgba by
+/// copying the luminance to all color channels. `alpha` holds the bytes of the
+/// value of a missing alpha channel and determines the size of a channel.
+pub fn luminance_to_rgba(
+    bytes: &[u8],
+    src_channels: usize,
+    alpha: &[u8],
+    width: usize,
+    height: usize,
+) -> Vec<u8> {
+    assert!(
+        src_channels == 1 || src_channels == 2,
+        "expected luminance or luminance alpha, got {} channels",
+        src_channels
+    );
+    let channel_size = alpha.len();
+    let src_pixel_size = src_channels * channel_size;
+    let dst_pixel_size = 4 * channel_size;
+    let mut rgba_bytes = vec![0u8; width * height * dst_pixel_size];
+
+    for (src, dst) in bytes
+        .chunks_exact(src_pixel_size)
+        .zip(rgba_bytes.chunks_exact_mut(dst_pixel_size))
+    {
+        let (luminance, src_alpha) = src.split_at(channel_size);
+        for channel in 0..3 {
+            dst[channel * channel_size..(channel + 1) * channel_size].copy_from_slice(luminance);
+        }
+        dst[3 * channel_size..].copy_from_slice(if src_channels == 2 { src_alpha } else { alpha });
+    }
+
+    rgba_bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packed_npot_rows() {
+        assert_eq!(validate_row_stride(3, 5, 4, None, 3 * 5 * 4), Ok(12));
+        assert_eq!(validate_row_stride(7, 1, 8, None, 7 * 8), Ok(56));
+
+        let copy = upload_region(vk::Format::R8G8B8A8_UNORM, 3, 5, None, 3 * 5 * 4).unwrap();
+        assert_eq!(copy.buffer_row_length, 3);
+    }
+
+    #[test]
+    fn padded_rows() {
+        // 3 pixels of 4 bytes padded to 16 bytes per row, with or without the
+        // padding after the last row.
+        assert_eq!(validate_row_stride(3, 5, 4, Some(16), 16 * 4 + 12), Ok(16));
+        assert_eq!(validate_row_stride(3, 5, 4, Some(16), 16 * 5), Ok(16));
+
+        let copy = upload_region(vk::Format::R8G8B8A8_UNORM, 3, 5, Some(16), 16 * 4 + 12).unwrap();
+        assert_eq!(copy.buffer_row_length, 4);
+        assert_eq!(copy.buffer_image_height, 5);
+        assert_eq!((copy.image_extent.width, copy.image_extent.height), (3, 5));
+
+        // 8 bytes per pixel, padded to 32 bytes per row
+        let copy = upload_region(vk::Format::R16G16B16A16_UNORM, 3, 2, Some(32), 32 * 2).unwrap();
+        assert_eq!(copy.buffer_row_length, 4);
+    }
+
+    #[test]
+    fn padded_sources() {
+        // 3 pixels of 4 bytes per row
+        assert_eq!(source_row_stride(12 * 5, 5, 12), None);
+        assert_eq!(source_row_stride(16 * 5, 5, 12), Some(16));
+        assert_eq!(source_row_stride(0, 0, 12), None);
+
+        let rows = [1, 2, 3, 0, 4, 5, 6, 0];
+        assert_eq!(strip_row_padding(&rows, 4, 3), [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn padded_channels() {
+        assert_eq!(
+            padd_pixel_bytes_rgba(&[1, 2, 3, 4], 2, &[0], &[255], 2, 1),
+            [1, 2, 0, 255, 3, 4, 0, 255]
+        );
+        assert_eq!(
+            padd_pixel_bytes_rgba(&[1, 0], 1, &[0xFF, 0xFF], &[0, 1], 1, 1),
+            [1, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0, 1]
+        );
+    }
+
+    #[test]
+    fn luminance_channels() {
+        assert_eq!(
+            luminance_to_rgba(&[7, 9], 1, &[255], 2, 1),
+            [7, 7, 7, 255, 9, 9, 9, 255]
+        );
+        assert_eq!(
+            luminance_to_rgba(&[7, 128], 2, &[255], 1, 1),
+            [7, 7, 7, 128]
+        );
+        assert_eq!(
+            luminance_to_rgba(&[1, 2, 3, 4], 2, &[0xFF, 0xFF], 1, 1),
+            [1, 2, 1, 2, 1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn empty_texture() {
+        assert_eq!(
+            validate_row_stride(4, 0, 4, None, 0),
+            Err(TextureError::Empty {
+                width: 4,
+                height: 0
+            })
+        );
+        assert!(upload_region(vk::Format::R8G8B8A8_UNORM, 0, 0, None, 0).is_err());
+    }
+
+    #[test]
+    fn unaligned_row_stride() {
+        assert!(matches!(
+            validate_row_stride(3, 5, 4, Some(14), 14 * 5),
+            Err(TextureError::RowStride { .. })
+        ));
+    }
+
+    #[test]
+    fn truncated_rows() {
+        assert_eq!(
+            validate_row_stride(3, 5, 4, Some(16), 16 * 4),
+            Err(TextureError::Size {
+                expected: 16 * 5,
+                len: 16 * 4
+            })
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use crate::{
+    blas::{RTXMaterial, BLAS},
     gltf_mesh::GltfModel,
-    ray_render_plugin::{Render, RenderConfig, RenderSet, TeardownSchedule},
+    ray_render_plugin::{Render, RenderAppExt, RenderConfig, RenderSet},
     raytracing_pipeline::{RTGroupHandle, RaytracingPipeline},
     render_buffer::{Buffer, BufferProvider},
     render_device::RenderDevice,
@@ -66,7 +67,7 @@ fn update_sbt(
     mut aligments: Local<SBTAligments>,
 ) {
     if !aligments.initialized {
-        let rtprops = vk_utils::get_raytracing_properties(&render_device);
+        let rtprops = render_device.ray_tracing_properties();
         aligments.shader_group_base_alignment = rtprops.shader_group_base_alignment as u64;
         aligments.shader_group_handle_alignment = rtprops.shader_group_handle_alignment as u64;
         aligments.initialized = true;
@@ -94,8 +95,9 @@ fn update_sbt(
         aligments.shader_group_base_alignment,
     );
 
-    // one extra for the sphere hit group
-    sbt.hit_region.size = sbt.hit_region.stride * (meshes.len() + gltf_meshes.len() + 1) as u64;
+    // the rays are traced with a record stride of 1, so every geometry of a mesh selects
+    // its own record, the first one is the sphere hit group.
+    sbt.hit_region.size = sbt.hit_region.stride * tlas.hit_record_count.max(1) as u64;
 
     let total_size = sbt.raygen_region.size + sbt.miss_region.size + sbt.hit_region.size;
 
@@ -130,17 +132,24 @@ fn update_sbt(
                 handle: rtx_pipeline.sphere_hit_handle,
             });
 
-            for (mesh_id, mesh) in meshes.iter() {
-                let mesh = match mesh {
-                    VulkanAssetLoadingState::Loading => continue,
-                    VulkanAssetLoadingState::Loaded(mesh) => mesh,
-                };
-
-                if let Some(offset) = tlas.mesh_to_hit_offset.get(&mesh_id.untyped()) {
-                    (dst.add(*offset as usize * sbt.hit_region.stride as usize)
-                        as *mut SBTRegionHitTriangle)
+            // the geometries of a mesh share the buffers, the hit group depends on the material
+            let write_mesh_records = |offset: u32, mesh: &BLAS| {
+                for geometry_idx in 0..mesh.geometry_count() {
+                    let transmissive = mesh
+                        .gltf_materials
+                        .as_ref()
+                        .and_then(|materials| materials.get(geometry_idx as usize))
+                        .is_some_and(RTXMaterial::is_transmissive);
+                    let handle = if transmissive {
+                        rtx_pipeline.transmissive_hit_handle
+                    } else {
+                        rtx_pipeline.hit_handle
+                    };
+
+                    let record = (offset + geometry_idx) as usize;
+                    (dst.add(record * sbt.hit_region.stride as usize) as *mut SBTRegionHitTriangle)
                         .write(SBTRegionHitTriangle {
-                            handle: rtx_pipeline.hit_handle,
+                            handle,
                             vertex_buffer: mesh.vertex_buffer.address,
                             triangle_buffer: mesh.triangle_buffer.address,
                             index_buffer: mesh.index_buffer.address,
@@ -148,6 +157,17 @@ fn update_sbt(
                             geometry_to_triangle: mesh.geometry_to_triangle.address,
                         });
                 }
+            };
+
+            for (mesh_id, mesh) in meshes.iter() {
+                let mesh = match mesh {
+                    VulkanAssetLoadingState::Loading => continue,
+                    VulkanAssetLoadingState::Loaded(mesh) => mesh,
+                };
+
+                if let Some(offset) = tlas.mesh_to_hit_offset.get(&mesh_id.untyped()) {
+                    write_mesh_records(*offset, mesh);
+                }
             }
 
             for (mesh_id, mesh) in gltf_meshes.iter() {
@@ -157,16 +177,7 @@ fn update_sbt(
                 };
 
                 if let Some(offset) = tlas.mesh_to_hit_offset.get(&mesh_id.untyped()) {
-                    (dst.add(*offset as usize * sbt.hit_region.stride as usize)
-                        as *mut SBTRegionHitTriangle)
-                        .write(SBTRegionHitTriangle {
-                            handle: rtx_pipeline.hit_handle,
-                            vertex_buffer: mesh.vertex_buffer.address,
-                            triangle_buffer: mesh.triangle_buffer.address,
-                            index_buffer: mesh.index_buffer.address,
-                            geometry_to_index: mesh.geometry_to_index.address,
-                            geometry_to_triangle: mesh.geometry_to_triangle.address,
-                        });
+                    write_mesh_records(*offset, mesh);
                 }
             }
         }
@@ -195,6 +206,6 @@ impl Plugin for SBTPlugin {
                 .after(poll_for_asset::<RaytracingPipeline>)
                 .after(update_tlas),
         );
-        render_app.add_systems(TeardownSchedule, cleanup_sbt);
+        app.add_render_teardown(cleanup_sbt);
     }
 }
@@ -0,0 +1,432 @@
+use std::{path::PathBuf, thread::JoinHandle};
+
+use ash::vk;
+use bevy::{
+    prelude::*,
+    render::{ExtractSchedule, RenderApp},
+};
+use gpu_allocator::MemoryLocation;
+
+use crate::{
+    extract::Extract,
+    ray_render_plugin::{Render, RenderAppExt, RenderSet},
+    render_buffer::{Buffer, BufferProvider},
+    render_device::RenderDevice,
+    vk_init, vk_utils,
+};
+
+/// What a [`Screenshot`] contains, both come from the `RenderConfig` of the frame
+/// and not from the dev ui, which doesn't need to exist.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScreenshotFormat {
+    /// The presented frame, tonemapped, without the dev ui.
+    #[default]
+    Png,
+    /// The accumulated linear radiance at the render resolution, scaled by the
+    /// exposure but not tonemapped.
+    Exr,
+}
+
+/// Saves the next frame to `path`.
+#[derive(Event, Clone, Debug)]
+pub struct Screenshot {
+    pub path: PathBuf,
+    pub format: ScreenshotFormat,
+}
+
+impl Screenshot {
+    pub fn png(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            format: ScreenshotFormat::Png,
+        }
+    }
+
+    pub fn exr(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            format: ScreenshotFormat::Exr,
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct PendingScreenshots {
+    /// Screenshots that are taken by the next frame.
+    pub screenshots: Vec<Screenshot>,
+    /// The threads still writing earlier screenshots, joined on teardown so none
+    /// is cut off when the app exits.
+    writers: Vec<JoinHandle<()>>,
+}
+
+impl PendingScreenshots {
+    /// Removes the screenshots of `format`, returning their paths.
+    pub fn take(&mut self, format: ScreenshotFormat) -> Vec<PathBuf> {
+        let (taken, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.screenshots)
+            .into_iter()
+            .partition(|screenshot| screenshot.format == format);
+        self.screenshots = pending;
+        taken
+            .into_iter()
+            .map(|screenshot| screenshot.path)
+            .collect()
+    }
+}
+
+/// The copy of an image, only valid once the frame that recorded it has finished.
+pub struct ScreenshotReadback {
+    buffer: Buffer<u8>,
+    extent: vk::Extent2D,
+    format: vk::Format,
+    /// Scales the linear (exr) pixels.
+    exposure: f32,
+    paths: Vec<PathBuf>,
+}
+
+/// The size of a pixel of the formats a screenshot can be taken of, any format
+/// [`crate::swapchain::Swapchain`] may end up with is either here or logged as
+/// unsupported.
+fn bytes_per_pixel(format: vk::Format) -> Option<u64> {
+    match format {
+        vk::Format::R8G8B8A8_UNORM
+        | vk::Format::R8G8B8A8_SRGB
+        | vk::Format::B8G8R8A8_UNORM
+        | vk::Format::B8G8R8A8_SRGB
+        | vk::Format::A8B8G8R8_UNORM_PACK32
+        | vk::Format::A8B8G8R8_SRGB_PACK32
+        | vk::Format::A2B10G10R10_UNORM_PACK32
+        | vk::Format::A2R10G10B10_UNORM_PACK32 => Some(4),
+        vk::Format::R16G16B16A16_SFLOAT => Some(8),
+        vk::Format::R32G32B32A32_SFLOAT => Some(16),
+        _ => None,
+    }
+}
+
+/// Converts the pixels of a swapchain image to rgba8. The image holds what is
+/// presented, so the values are already encoded for display in every format.
+fn to_rgba8(format: vk::Format, pixels: &[u8]) -> Vec<u8> {
+    let unorm8 = |value: f32| (value.clamp(0.0, 1.0) * 255.0 + 0.5) as u8;
+    let unorm10 = |packed: u32, shift: u32| unorm8(((packed >> shift) & 0x3FF) as f32 / 1023.0);
+    match format {
+        vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => pixels
+            .chunks_exact(4)
+            .flat_map(|pixel| [pixel[2], pixel[1], pixel[0], pixel[3]])
+            .collect(),
+        vk::Format::A2B10G10R10_UNORM_PACK32 | vk::Format::A2R10G10B10_UNORM_PACK32 => pixels
+            .chunks_exact(4)
+            .flat_map(|pixel| {
+                let packed = u32::from_ne_bytes(pixel.try_into().unwrap());
+                let alpha = unorm8((packed >> 30) as f32 / 3.0);
+                let (r, g, b) = (unorm10(packed, 0), unorm10(packed, 10), unorm10(packed, 20));
+                if format == vk::Format::A2B10G10R10_UNORM_PACK32 {
+                    [r, g, b, alpha]
+                } else {
+                    [b, g, r, alpha]
+                }
+            })
+            .collect(),
+        vk::Format::R16G16B16A16_SFLOAT => pixels
+            .chunks_exact(2)
+            .map(|half| unorm8(f16_to_f32(u16::from_ne_bytes(half.try_into().unwrap()))))
+            .collect(),
+        // the 8 bit rgba formats
+        _ => pixels.to_vec(),
+    }
+}
+
+fn f16_to_f32(half: u16) -> f32 {
+    let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((half >> 10) & 0x1F) as i32;
+    let mantissa = (half & 0x3FF) as f32;
+    match exponent {
+        0 => sign * mantissa * 2f32.powi(-24),
+        0x1F if mantissa == 0.0 => sign * f32::INFINITY,
+        0x1F => f32::NAN,
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+/// Copies `image` (in `TRANSFER_SRC_OPTIMAL` or `GENERAL`) to a host visible buffer.
+/// Images of format `R32G32B32A32_SFLOAT` are saved as exr, anything else as png.
+/// `format` must be supported, see [`bytes_per_pixel`].
+pub unsafe fn cmd_copy_image(
+    render_device: &RenderDevice,
+    cmd_buffer: vk::CommandBuffer,
+    image: vk::Image,
+    layout: vk::ImageLayout,
+    extent: vk::Extent2D,
+    format: vk::Format,
+    paths: Vec<PathBuf>,
+) -> ScreenshotReadback {
+    let bytes_per_pixel = bytes_per_pixel(format).expect("unsupported screenshot format");
+    let buffer: Buffer<u8> = render_device.create_buffer(
+        extent.width as u64 * extent.height as u64 * bytes_per_pixel,
+        vk::BufferUsageFlags::TRANSFER_DST,
+        MemoryLocation::GpuToCpu,
+    );
+
+    render_device.cmd_copy_image_to_buffer(
+        cmd_buffer,
+        image,
+        layout,
+        buffer.handle,
+        std::slice::from_ref(&vk_init::buffer_image_copy(extent.width, extent.height, 0)),
+    );
+
+    ScreenshotReadback {
+        buffer,
+        extent,
+        format,
+        exposure: 1.0,
+        paths,
+    }
+}
+
+impl ScreenshotReadback {
+    /// Must be called after the commands recorded by [`cmd_copy_image`] have completed.
+    /// The image is encoded and written on a separate thread, kept in `pending`.
+    pub fn save(mut self, render_device: &RenderDevice, pending: &mut PendingScreenshots) {
+        pending.writers.retain(|writer| !writer.is_finished());
+
+        let pixels = render_device
+            .map_buffer(&mut self.buffer)
+            .as_slice_mut()
+            .to_vec();
+        render_device.destroyer.destroy_buffer(self.buffer.handle);
+
+        let (width, height) = (self.extent.width, self.extent.height);
+        if self.format == vk::Format::R32G32B32A32_SFLOAT {
+            let pixels: Vec<f32> = pixels
+                .chunks_exact(4)
+                .map(|bytes| f32::from_ne_bytes(bytes.try_into().unwrap()))
+                .collect();
+            let exposure = self.exposure;
+            pending.writers.push(spawn_writer(self.paths, move |path| {
+                write_exr(path, width, height, &pixels, exposure)
+            }));
+            return;
+        }
+
+        let pixels = to_rgba8(self.format, &pixels);
+        pending.writers.push(spawn_writer(self.paths, move |path| {
+            write_png(path, width, height, &pixels)
+        }));
+    }
+}
+
+fn spawn_writer<E: std::fmt::Display>(
+    paths: Vec<PathBuf>,
+    write: impl Fn(&PathBuf) -> Result<(), E> + Send + 'static,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        for path in paths {
+            match write(&path) {
+                Ok(()) => log::info!("Saved screenshot to {}", path.display()),
+                Err(e) => log::error!("Failed to save screenshot {}: {}", path.display(), e),
+            }
+        }
+    });
+}
+
+fn write_png(path: &PathBuf, width: u32, height: u32, pixels: &[u8]) -> std::io::Result<()> {
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let mut encoder = png::Encoder::new(file, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels)?;
+    Ok(())
+}
+
+/// `pixels` is the render target, which sums the frames and counts them in alpha.
+fn write_exr(
+    path: &PathBuf,
+    width: u32,
+    height: u32,
+    pixels: &[f32],
+    exposure: f32,
+) -> exr::error::UnitResult {
+    exr::prelude::write_rgb_file(path, width as usize, height as usize, |x, y| {
+        let i = (y * width as usize + x) * 4;
+        let scale = exposure / pixels[i + 3].max(1.0);
+        (
+            pixels[i] * scale,
+            pixels[i + 1] * scale,
+            pixels[i + 2] * scale,
+        )
+    })
+}
+
+/// Transitions the swapchain image from `ATTACHMENT_OPTIMAL` to `PRESENT_SRC_KHR`,
+/// copying it on the way if any screenshots are pending. `pending` is `None` without
+/// the [`ScreenshotPlugin`].
+pub unsafe fn cmd_present_and_capture(
+    render_device: &RenderDevice,
+    cmd_buffer: vk::CommandBuffer,
+    image: vk::Image,
+    extent: vk::Extent2D,
+    format: vk::Format,
+    pending: Option<&mut PendingScreenshots>,
+) -> Option<ScreenshotReadback> {
+    let mut paths = pending.map_or_else(Vec::new, |pending| pending.take(ScreenshotFormat::Png));
+    if !paths.is_empty() && bytes_per_pixel(format).is_none() {
+        log::error!(
+            "Screenshots of swapchain format {:?} are not supported, dropping png screenshots",
+            format
+        );
+        paths.clear();
+    }
+    if paths.is_empty() {
+        vk_utils::transition_image_layout(
+            render_device,
+            cmd_buffer,
+            image,
+            vk::ImageLayout::ATTACHMENT_OPTIMAL,
+            vk::ImageLayout::PRESENT_SRC_KHR,
+        );
+        return None;
+    }
+
+    vk_utils::transition_image_layout(
+        render_device,
+        cmd_buffer,
+        image,
+        vk::ImageLayout::ATTACHMENT_OPTIMAL,
+        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+    );
+    let readback = cmd_copy_image(
+        render_device,
+        cmd_buffer,
+        image,
+        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        extent,
+        format,
+        paths,
+    );
+    vk_utils::transition_image_layout(
+        render_device,
+        cmd_buffer,
+        image,
+        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        vk::ImageLayout::PRESENT_SRC_KHR,
+    );
+    Some(readback)
+}
+
+/// Copies the render target (in `GENERAL`) once the trace has written it, if any
+/// exr screenshots are pending.
+pub unsafe fn cmd_capture_render_target(
+    render_device: &RenderDevice,
+    cmd_buffer: vk::CommandBuffer,
+    image: vk::Image,
+    extent: vk::Extent2D,
+    exposure: f32,
+    pending: Option<&mut PendingScreenshots>,
+) -> Option<ScreenshotReadback> {
+    let paths = pending.map_or_else(Vec::new, |pending| pending.take(ScreenshotFormat::Exr));
+    if paths.is_empty() {
+        return None;
+    }
+
+    vk_utils::trace_to_copy_barrier(render_device, cmd_buffer, image);
+    let mut readback = cmd_copy_image(
+        render_device,
+        cmd_buffer,
+        image,
+        vk::ImageLayout::GENERAL,
+        extent,
+        vk::Format::R32G32B32A32_SFLOAT,
+        paths,
+    );
+    readback.exposure = exposure;
+    Some(readback)
+}
+
+pub struct ScreenshotPlugin;
+
+impl Plugin for ScreenshotPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<Screenshot>();
+
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app.init_resource::<PendingScreenshots>();
+        render_app.add_systems(ExtractSchedule, extract_screenshots);
+        render_app.add_systems(Render, warn_unsupported.in_set(RenderSet::Prepare));
+        app.add_render_teardown(join_writers);
+    }
+}
+
+fn join_writers(world: &mut World) {
+    let Some(mut pending) = world.get_resource_mut::<PendingScreenshots>() else {
+        return;
+    };
+    for writer in pending.writers.drain(..) {
+        if writer.join().is_err() {
+            log::error!("A screenshot writer panicked");
+        }
+    }
+}
+
+fn extract_screenshots(
+    mut screenshots: Extract<EventReader<Screenshot>>,
+    mut pending: ResMut<PendingScreenshots>,
+) {
+    pending.screenshots.extend(screenshots.read().cloned());
+}
+
+fn warn_unsupported(
+    swapchain: Option<Res<crate::swapchain::Swapchain>>,
+    mut pending: ResMut<PendingScreenshots>,
+) {
+    let Some(swapchain) = swapchain else {
+        return;
+    };
+
+    if !swapchain.supports_screenshots && !pending.take(ScreenshotFormat::Png).is_empty() {
+        log::error!("The swapchain cannot be copied from, dropping png screenshots");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swapchain_formats_to_rgba8() {
+        let rgba = [10, 20, 30, 255];
+        assert_eq!(to_rgba8(vk::Format::R8G8B8A8_SRGB, &rgba), rgba);
+        assert_eq!(
+            to_rgba8(vk::Format::B8G8R8A8_UNORM, &[30, 20, 10, 255]),
+            rgba
+        );
+
+        // 1023 in the lowest 10 bits, 0 in the middle ones and 512 in the highest
+        let packed = (1023u32 | 512 << 20 | 3 << 30).to_ne_bytes();
+        assert_eq!(
+            to_rgba8(vk::Format::A2B10G10R10_UNORM_PACK32, &packed),
+            [255, 0, 128, 255]
+        );
+        assert_eq!(
+            to_rgba8(vk::Format::A2R10G10B10_UNORM_PACK32, &packed),
+            [128, 0, 255, 255]
+        );
+
+        // 1.0, 0.5, 0.0 and 2.0 as halfs
+        let halfs: Vec<u8> = [0x3C00u16, 0x3800, 0x0000, 0x4000]
+            .iter()
+            .flat_map(|half| half.to_ne_bytes())
+            .collect();
+        assert_eq!(
+            to_rgba8(vk::Format::R16G16B16A16_SFLOAT, &halfs),
+            [255, 128, 0, 255]
+        );
+    }
+
+    #[test]
+    fn screenshot_pixel_sizes() {
+        for format in [vk::Format::B8G8R8A8_UNORM, vk::Format::R8G8B8A8_UNORM] {
+            assert_eq!(bytes_per_pixel(format), Some(4));
+        }
+        assert_eq!(bytes_per_pixel(vk::Format::R5G6B5_UNORM_PACK16), None);
+    }
+}
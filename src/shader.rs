@@ -74,6 +74,7 @@ impl AssetLoader for ShaderLoader {
                 "rgen" => shaderc::ShaderKind::RayGeneration,
                 "rint" => shaderc::ShaderKind::Intersection,
                 "rchit" => shaderc::ShaderKind::ClosestHit,
+                "rahit" => shaderc::ShaderKind::AnyHit,
                 "rmiss" => shaderc::ShaderKind::Miss,
                 _ => panic!("Unsupported shader extension: {}", ext),
             };
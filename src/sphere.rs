@@ -3,12 +3,18 @@ use bevy::{prelude::*, render::RenderApp};
 
 use crate::{
     blas::{allocate_acceleration_structure, AccelerationStructure},
-    extract::Extract,
+    extract::{Extract, MainEntity},
+    motion_blur::PreviousGlobalTransform,
     render_buffer::{Buffer, BufferProvider},
     render_device::RenderDevice,
+    tlas_builder::InstanceOpacity,
 };
 
+/// A sphere of diameter 1 around the origin, rendered with the `MeshMaterial3d<StandardMaterial>`
+/// on the same entity. Requires `Transform` (and through it `GlobalTransform`), so spawning
+/// `(Sphere, material)` places it at the origin.
 #[derive(Component, Default, Clone)]
+#[require(Transform)]
 pub struct Sphere;
 
 pub struct SpherePlugin;
@@ -164,18 +170,29 @@ impl SphereBLAS {
     }
 }
 
-fn extract_spheres(
+pub(crate) fn extract_spheres(
     mut commands: Commands,
     meshes: Extract<
         Query<(
+            Entity,
             &Sphere,
             &MeshMaterial3d<StandardMaterial>,
             &Transform,
             &GlobalTransform,
+            Option<&InstanceOpacity>,
+            Option<&PreviousGlobalTransform>,
         )>,
     >,
 ) {
-    for (sphere, mat, t, gt) in meshes.iter() {
-        commands.spawn((sphere.clone(), mat.clone(), t.clone(), gt.clone()));
+    for (e, sphere, mat, t, gt, opacity, previous) in meshes.iter() {
+        commands.spawn((
+            MainEntity(e),
+            sphere.clone(),
+            mat.clone(),
+            t.clone(),
+            gt.clone(),
+            opacity.copied().unwrap_or_default(),
+            previous.copied().unwrap_or(PreviousGlobalTransform(*gt)),
+        ));
     }
 }
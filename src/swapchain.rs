@@ -6,7 +6,7 @@ use raw_window_handle::{HasDisplayHandle, HasWindowHandle};
 use crate::ray_render_plugin::ExtractedWindow;
 use crate::render_device::RenderDevice;
 
-const FRAMES_IN_FLIGHT: usize = 1;
+pub const FRAMES_IN_FLIGHT: usize = 1;
 
 #[derive(Resource)]
 pub struct Swapchain {
@@ -16,12 +16,21 @@ pub struct Swapchain {
     pub swapchain_images: Vec<vk::Image>,
     pub swapchain_image_views: Vec<vk::ImageView>,
     pub swapchain_extent: vk::Extent2D,
+    pub surface_format: vk::Format,
+    /// Whether the images can be copied from, required for screenshots.
+    pub supports_screenshots: bool,
     pub current_image_idx: u32,
     pub image_available_semaphore: vk::Semaphore,
     pub render_finished_semaphore: vk::Semaphore,
     pub in_flight_fences: [vk::Fence; FRAMES_IN_FLIGHT],
     pub resized: bool,
+    /// Set when the swapchain could not be (re)created because the surface
+    /// has no area, i.e. the window is minimized.
+    pub out_of_date: bool,
     pub frame_count: usize,
+    /// Whether the swapchain was created for an exclusive fullscreen window.
+    pub exclusive_fullscreen: bool,
+    exclusive_fullscreen_acquired: bool,
 }
 
 unsafe fn create_surface(
@@ -73,12 +82,17 @@ impl Swapchain {
             swapchain_images: Vec::new(),
             swapchain_image_views: Vec::new(),
             swapchain_extent: vk::Extent2D::default(),
+            surface_format: vk::Format::UNDEFINED,
+            supports_screenshots: false,
             image_available_semaphore,
             render_finished_semaphore,
             current_image_idx: 0,
             in_flight_fences,
             resized: false,
+            out_of_date: false,
             frame_count: 0,
+            exclusive_fullscreen: false,
+            exclusive_fullscreen_acquired: false,
         }
     }
 
@@ -127,7 +141,23 @@ impl Swapchain {
             _ => surface_caps.current_extent,
         };
 
+        if surface_resolution.width == 0 || surface_resolution.height == 0 {
+            // keep the old swapchain around until the window is restored
+            self.out_of_date = true;
+            return;
+        }
+        self.out_of_date = false;
+
         self.swapchain_extent = surface_resolution;
+        self.surface_format = surface_format.format;
+        self.supports_screenshots = surface_caps
+            .supported_usage_flags
+            .contains(vk::ImageUsageFlags::TRANSFER_SRC);
+        let image_usage = if self.supports_screenshots {
+            vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC
+        } else {
+            vk::ImageUsageFlags::COLOR_ATTACHMENT
+        };
 
         let pre_transform = if surface_caps
             .supported_transforms
@@ -151,14 +181,30 @@ impl Swapchain {
 
         log::info!("Present mode: {:?}", present_mode);
 
+        // exclusive mode belongs to the swapchain that is about to be retired
+        self.release_full_screen_exclusive();
+
+        let use_full_screen_exclusive = window.exclusive_fullscreen
+            && window.hmonitor != 0
+            && self.device.ext_full_screen_exclusive.is_some();
+        if window.exclusive_fullscreen && !use_full_screen_exclusive {
+            log::warn!("Exclusive fullscreen is not supported, presenting through the compositor");
+        }
+
+        let mut full_screen_exclusive_info = vk::SurfaceFullScreenExclusiveInfoEXT::default()
+            .full_screen_exclusive(vk::FullScreenExclusiveEXT::APPLICATION_CONTROLLED);
+        let mut full_screen_exclusive_win32_info =
+            vk::SurfaceFullScreenExclusiveWin32InfoEXT::default()
+                .hmonitor(window.hmonitor as vk::HMONITOR);
+
         let old_swapchain = self.swapchain;
-        let swapchain_create_info = vk::SwapchainCreateInfoKHR::default()
+        let mut swapchain_create_info = vk::SwapchainCreateInfoKHR::default()
             .surface(self.surface)
             .min_image_count(desired_image_count)
             .image_color_space(surface_format.color_space)
             .image_format(surface_format.format)
             .image_extent(surface_resolution)
-            .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
+            .image_usage(image_usage)
             .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
             .pre_transform(pre_transform)
             .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
@@ -167,12 +213,30 @@ impl Swapchain {
             .image_array_layers(1)
             .old_swapchain(old_swapchain);
 
+        if use_full_screen_exclusive {
+            swapchain_create_info = swapchain_create_info
+                .push_next(&mut full_screen_exclusive_info)
+                .push_next(&mut full_screen_exclusive_win32_info);
+        }
+
         self.swapchain = self
             .device
             .ext_swapchain
             .create_swapchain(&swapchain_create_info, None)
             .unwrap();
 
+        self.exclusive_fullscreen = window.exclusive_fullscreen;
+        if use_full_screen_exclusive {
+            let ext = self.device.ext_full_screen_exclusive.as_ref().unwrap();
+            match ext.acquire_full_screen_exclusive_mode(self.swapchain) {
+                Ok(()) => {
+                    log::info!("Acquired exclusive fullscreen");
+                    self.exclusive_fullscreen_acquired = true;
+                }
+                Err(e) => log::warn!("Failed to acquire exclusive fullscreen: {:?}", e),
+            }
+        }
+
         self.device.destroyer.destroy_swapchain(old_swapchain);
         for image_view in self.swapchain_image_views.drain(..) {
             self.device.destroyer.destroy_image_view(image_view);
@@ -202,16 +266,26 @@ impl Swapchain {
         );
     }
 
-    pub unsafe fn aquire_next_image(
-        &mut self,
-        window: &ExtractedWindow,
-    ) -> (vk::Image, vk::ImageView) {
-        if self.swapchain == vk::SwapchainKHR::null() {
-            self.on_resize(window);
-            self.resized = true;
+    unsafe fn release_full_screen_exclusive(&mut self) {
+        if !self.exclusive_fullscreen_acquired {
+            return;
         }
-        self.current_image_idx = self
-            .device
+
+        self.exclusive_fullscreen_acquired = false;
+        if let Some(ext) = &self.device.ext_full_screen_exclusive {
+            let _ = ext.release_full_screen_exclusive_mode(self.swapchain);
+        }
+    }
+
+    unsafe fn on_full_screen_exclusive_lost(&mut self) {
+        // The swapchain remains usable, it is just presented through the compositor again.
+        // We'll try to reacquire the next time the swapchain gets recreated.
+        log::warn!("Lost exclusive fullscreen");
+        self.release_full_screen_exclusive();
+    }
+
+    unsafe fn acquire_image_index(&self) -> Result<u32, vk::Result> {
+        self.device
             .ext_swapchain
             .acquire_next_image(
                 self.swapchain,
@@ -219,8 +293,42 @@ impl Swapchain {
                 self.image_available_semaphore,
                 vk::Fence::null(),
             )
-            .unwrap()
-            .0;
+            .map(|(idx, _suboptimal)| idx)
+    }
+
+    /// Returns `None` when there is nothing to present to, the frame should be skipped.
+    pub unsafe fn aquire_next_image(
+        &mut self,
+        window: &ExtractedWindow,
+    ) -> Option<(vk::Image, vk::ImageView)> {
+        if window.minimized {
+            return None;
+        }
+
+        if self.swapchain == vk::SwapchainKHR::null()
+            || self.out_of_date
+            || self.exclusive_fullscreen != window.exclusive_fullscreen
+        {
+            self.on_resize(window);
+            self.resized = true;
+            if self.out_of_date || self.swapchain == vk::SwapchainKHR::null() {
+                return None;
+            }
+        }
+
+        self.current_image_idx = match self.acquire_image_index() {
+            Ok(idx) => idx,
+            Err(vk::Result::ERROR_FULL_SCREEN_EXCLUSIVE_MODE_LOST_EXT) => {
+                self.on_full_screen_exclusive_lost();
+                self.acquire_image_index().ok()?
+            }
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                log::debug!("------ SWAPCHAIN OUT OF DATE ------");
+                self.out_of_date = true;
+                return None;
+            }
+            Err(e) => panic!("Failed to acquire swapchain image: {:?}", e),
+        };
 
         self.device
             .wait_for_fences(
@@ -235,10 +343,10 @@ impl Swapchain {
             ))
             .unwrap();
 
-        return (
+        Some((
             self.swapchain_images[self.current_image_idx as usize],
             self.swapchain_image_views[self.current_image_idx as usize],
-        );
+        ))
     }
 
     pub unsafe fn submit_presentation(
@@ -282,6 +390,9 @@ impl Swapchain {
                 self.on_resize(window);
                 self.resized = true;
             }
+            Err(vk::Result::ERROR_FULL_SCREEN_EXCLUSIVE_MODE_LOST_EXT) => {
+                self.on_full_screen_exclusive_lost();
+            }
             Err(e) => panic!("Failed to present swapchain image: {:?}", e),
             Ok(_) => {
                 self.resized = false;
@@ -309,6 +420,8 @@ impl Drop for Swapchain {
                 self.device.destroy_fence(*fence, None);
             }
 
+            self.release_full_screen_exclusive();
+
             for &image_view in self.swapchain_image_views.iter() {
                 self.device.destroy_image_view(image_view, None);
             }
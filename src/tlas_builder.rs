@@ -1,61 +1,120 @@
 use crate::{
     blas::RTXMaterial,
+    extract::MainEntity,
     gltf_mesh::{GltfModel, GltfModelHandle},
-    ray_render_plugin::TeardownSchedule,
+    motion_blur::{lerp_affine, PreviousGlobalTransform},
+    ray_render_plugin::RenderAppExt,
     render_buffer::BufferProvider,
     sphere::SphereBLAS,
     vk_utils,
 };
 use ash::vk;
-use bevy::{asset::UntypedAssetId, prelude::*, render::RenderApp, utils::HashMap};
+use bevy::{
+    asset::UntypedAssetId,
+    math::Affine3A,
+    prelude::*,
+    render::RenderApp,
+    utils::{HashMap, HashSet},
+};
 
 use crate::{
     blas::AccelerationStructure,
-    ray_render_plugin::{Render, RenderSet},
+    ray_render_plugin::{Render, RenderConfig, RenderSet},
     render_buffer::Buffer,
     render_device::RenderDevice,
     vulkan_asset::VulkanAssets,
 };
 
+/// Multiplies the opacity of everything the entity renders, independent of the
+/// material. Intersections are discarded at random (with blue noise) in the any hit
+/// shader, so the instance fades out smoothly once enough frames are accumulated.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct InstanceOpacity(pub f32);
+
+impl Default for InstanceOpacity {
+    fn default() -> Self {
+        Self(1.0)
+    }
+}
+
+/// Per instance data, indexed by the custom index of the instance.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct InstanceData {
+    pub opacity: f32,
+    /// The material of the first geometry, followed by one for each other geometry.
+    pub material_offset: u32,
+}
+
 #[derive(Default, Resource)]
 pub struct TLAS {
     pub acceleration_structure: AccelerationStructure,
     pub instance_buffer: Buffer<vk::AccelerationStructureInstanceKHR>,
     pub scratch_buffer: Buffer<u8>,
+    /// The first SBT hit record of a mesh, followed by one for each other geometry.
     pub mesh_to_hit_offset: HashMap<UntypedAssetId, u32>,
+    /// Records in the hit region of the SBT, the sphere record included.
+    pub hit_record_count: u32,
     pub material_buffer: Buffer<RTXMaterial>,
+    pub instance_data_buffer: Buffer<InstanceData>,
+    /// Whether any instance has an [`InstanceOpacity`] below 1, the any hit
+    /// shader only runs for rays that are not forced to be opaque.
+    pub has_faded_instances: bool,
+    /// Whether any instance has geometry that is not opaque (alpha masked), which
+    /// needs the any hit shader as well.
+    pub has_non_opaque_geometry: bool,
+    /// The instances of the last build, with the previous and current transform of
+    /// each, see [`TLAS::instances_at`].
+    pub instances: Vec<vk::AccelerationStructureInstanceKHR>,
+    pub motion: Vec<(Affine3A, Affine3A)>,
+}
+
+pub type TLASInstance = (
+    vk::AccelerationStructureInstanceKHR,
+    InstanceData,
+    Vec<RTXMaterial>,
+);
+
+fn vk_transform(affine: &Affine3A) -> vk::TransformMatrixKHR {
+    let columns = affine.to_cols_array_2d();
+    vk::TransformMatrixKHR {
+        matrix: [
+            columns[0][0],
+            columns[1][0],
+            columns[2][0],
+            columns[3][0],
+            columns[0][1],
+            columns[1][1],
+            columns[2][1],
+            columns[3][1],
+            columns[0][2],
+            columns[1][2],
+            columns[2][2],
+            columns[3][2],
+        ],
+    }
 }
 
 impl TLAS {
-    pub fn update(
-        &mut self,
-        render_device: &RenderDevice,
-        instances: &[(vk::AccelerationStructureInstanceKHR, Vec<RTXMaterial>)],
-    ) {
+    pub fn update(&mut self, render_device: &RenderDevice, instances: &[TLASInstance]) {
         if instances.is_empty() {
             return;
         }
 
         let materials = instances
             .iter()
-            .map(|(_, m)| m.iter().cloned())
+            .map(|(_, _, m)| m.iter().cloned())
             .flatten()
             .collect::<Vec<_>>();
-        // recreate the index buffer and material if the number of instances changed
-        if instances.len() != self.instance_buffer.nr_elements as usize {
-            log::debug!(
-                "Reallocting instance buffer from {} to {} elements",
-                self.instance_buffer.nr_elements,
-                instances.len()
-            );
+        // recreate the instance data buffer if the number of instances changed
+        if instances.len() != self.instance_data_buffer.nr_elements as usize {
             render_device
                 .destroyer
-                .destroy_buffer(self.instance_buffer.handle);
-            self.instance_buffer = render_device
-                .create_host_buffer::<vk::AccelerationStructureInstanceKHR>(
-                    instances.len() as u64,
-                    vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR,
-                );
+                .destroy_buffer(self.instance_data_buffer.handle);
+            self.instance_data_buffer = render_device.create_host_buffer::<InstanceData>(
+                instances.len() as u64,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+            );
         }
 
         if materials.len() != self.material_buffer.nr_elements as usize {
@@ -74,11 +133,11 @@ impl TLAS {
             );
         }
 
-        // update the instance buffer
+        // update the instance data buffer
         {
-            let instances = instances.iter().map(|(i, _)| *i).collect::<Vec<_>>();
-            let mut ptr = render_device.map_buffer(&mut self.instance_buffer);
-            ptr.copy_from_slice(&instances);
+            let instance_data = instances.iter().map(|(_, d, _)| *d).collect::<Vec<_>>();
+            let mut ptr = render_device.map_buffer(&mut self.instance_data_buffer);
+            ptr.copy_from_slice(&instance_data);
         }
 
         // update the material buffer
@@ -87,6 +146,48 @@ impl TLAS {
             ptr.copy_from_slice(&materials);
         }
 
+        let instances = instances.iter().map(|(i, _, _)| *i).collect::<Vec<_>>();
+        render_device.run_transfer_commands(|cmd_buffer| {
+            self.cmd_build(render_device, cmd_buffer, &instances);
+        });
+    }
+
+    /// Records a rebuild of only the acceleration structure, the material and
+    /// instance data buffers are left as they are. The instance buffer is written
+    /// right away, so the GPU must be done with the previous build.
+    pub fn cmd_build(
+        &mut self,
+        render_device: &RenderDevice,
+        cmd_buffer: vk::CommandBuffer,
+        instances: &[vk::AccelerationStructureInstanceKHR],
+    ) {
+        if instances.is_empty() {
+            return;
+        }
+
+        // recreate the instance buffer if the number of instances changed
+        if instances.len() != self.instance_buffer.nr_elements as usize {
+            log::debug!(
+                "Reallocting instance buffer from {} to {} elements",
+                self.instance_buffer.nr_elements,
+                instances.len()
+            );
+            render_device
+                .destroyer
+                .destroy_buffer(self.instance_buffer.handle);
+            self.instance_buffer = render_device
+                .create_host_buffer::<vk::AccelerationStructureInstanceKHR>(
+                    instances.len() as u64,
+                    vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR,
+                );
+        }
+
+        // update the instance buffer
+        {
+            let mut ptr = render_device.map_buffer(&mut self.instance_buffer);
+            ptr.copy_from_slice(instances);
+        }
+
         let geometry = vk::AccelerationStructureGeometryKHR::default()
             .geometry_type(vk::GeometryTypeKHR::INSTANCES)
             .flags(vk::GeometryFlagsKHR::OPAQUE)
@@ -141,7 +242,7 @@ impl TLAS {
         }
         .unwrap();
 
-        let as_properties = vk_utils::get_acceleration_structure_properties(&render_device);
+        let as_properties = render_device.acceleration_structure_properties();
         let scratch_alignment =
             as_properties.min_acceleration_structure_scratch_offset_alignment as u64;
         let scratch_size = vk_utils::aligned_size(build_size.build_scratch_size, scratch_alignment);
@@ -182,15 +283,15 @@ impl TLAS {
             .transform_offset(0);
 
         let build_range_infos = std::slice::from_ref(&build_range);
-        render_device.run_transfer_commands(&|command_buffer| unsafe {
+        unsafe {
             render_device
                 .ext_acc_struct
                 .cmd_build_acceleration_structures(
-                    command_buffer,
+                    cmd_buffer,
                     std::slice::from_ref(&build_geometry),
                     std::slice::from_ref(&build_range_infos),
                 );
-        });
+        }
 
         self.acceleration_structure.address = unsafe {
             render_device
@@ -201,6 +302,155 @@ impl TLAS {
                 )
         };
     }
+
+    /// The instances of the last build with their transforms interpolated from the
+    /// previous (`t` = 0) to the current frame (`t` = 1).
+    pub fn instances_at(&self, t: f32) -> Vec<vk::AccelerationStructureInstanceKHR> {
+        self.instances
+            .iter()
+            .zip(&self.motion)
+            .map(|(instance, (previous, current))| {
+                let mut instance = *instance;
+                instance.transform = vk_transform(&lerp_affine(previous, current, t));
+                instance
+            })
+            .collect()
+    }
+
+    pub fn destroy(&self, render_device: &RenderDevice) {
+        render_device
+            .destroyer
+            .destroy_acceleration_structure(self.acceleration_structure.handle);
+        render_device
+            .destroyer
+            .destroy_buffer(self.acceleration_structure.buffer.handle);
+        render_device
+            .destroyer
+            .destroy_buffer(self.instance_buffer.handle);
+        render_device
+            .destroyer
+            .destroy_buffer(self.scratch_buffer.handle);
+        render_device
+            .destroyer
+            .destroy_buffer(self.material_buffer.handle);
+        render_device
+            .destroyer
+            .destroy_buffer(self.instance_data_buffer.handle);
+    }
+}
+
+/// Everything the TLAS instance of an entity is built from.
+struct TLASObject {
+    entity: Entity,
+    main_entity: Entity,
+    /// The mesh and its number of geometries, the sphere hit group is shared.
+    mesh: Option<(UntypedAssetId, u32)>,
+    hit_offset: u32,
+    transform: GlobalTransform,
+    reference: vk::AccelerationStructureReferenceKHR,
+    materials: Vec<RTXMaterial>,
+    opacity: f32,
+    opaque: bool,
+}
+
+/// Objects that were kept last frame count as this much closer when leaving out
+/// instances, so objects near the cutoff don't flicker in and out as the camera moves.
+const KEPT_DISTANCE_SCALE: f32 = 0.8;
+
+/// Leaves out the objects furthest away from `eye` when there are more than
+/// `max_instances`, the rest keep their order. `kept` holds the main entities that
+/// were kept the previous frame and is updated.
+fn limit_instances(
+    objects: &mut Vec<TLASObject>,
+    eye: Vec3,
+    max_instances: usize,
+    kept: &mut HashSet<Entity>,
+) {
+    if objects.len() <= max_instances {
+        kept.clear();
+        return;
+    }
+
+    let mut order: Vec<(f32, Entity, usize)> = objects
+        .iter()
+        .enumerate()
+        .map(|(i, object)| {
+            let mut distance = object.transform.translation().distance(eye);
+            if kept.contains(&object.main_entity) {
+                distance *= KEPT_DISTANCE_SCALE;
+            }
+            (distance, object.main_entity, i)
+        })
+        .collect();
+    // the entity breaks ties, so equally distant objects are not left out at random
+    order.sort_by(|(a, a_entity, _), (b, b_entity, _)| a.total_cmp(b).then(a_entity.cmp(b_entity)));
+
+    let mut keep = vec![false; objects.len()];
+    for (_, _, i) in &order[..max_instances] {
+        keep[*i] = true;
+    }
+    let mut keep = keep.into_iter();
+    objects.retain(|_| keep.next().unwrap());
+
+    *kept = objects.iter().map(|object| object.main_entity).collect();
+}
+
+/// Gives the meshes of `objects` their SBT hit records, the first one is reserved for
+/// the sphere hit group. Returns the number of hit records.
+fn assign_hit_offsets(
+    objects: &mut [TLASObject],
+    mesh_to_hit_offset: &mut HashMap<UntypedAssetId, u32>,
+) -> u32 {
+    mesh_to_hit_offset.clear();
+    let mut hit_group_offset_gen = 1;
+    for object in objects {
+        let Some((mesh, geometry_count)) = object.mesh else {
+            object.hit_offset = 0;
+            continue;
+        };
+        object.hit_offset = *mesh_to_hit_offset.entry(mesh).or_insert_with(|| {
+            let old_val = hit_group_offset_gen;
+            hit_group_offset_gen += geometry_count.max(1);
+            old_val
+        });
+    }
+    hit_group_offset_gen
+}
+
+/// The custom index of instance `i` is `i` itself, the shaders look up the
+/// [`InstanceData`] with it and the materials through its `material_offset`.
+fn build_instances(objects: &[TLASObject]) -> Vec<TLASInstance> {
+    let mut material_offset = 0;
+    objects
+        .iter()
+        .enumerate()
+        .map(|(i, object)| {
+            // the any hit shader is skipped for opaque geometry
+            let mut flags = vk::GeometryInstanceFlagsKHR::TRIANGLE_FACING_CULL_DISABLE;
+            if object.opacity < 1.0 {
+                flags |= vk::GeometryInstanceFlagsKHR::FORCE_NO_OPAQUE;
+            }
+            // fully transparent instances are never hit
+            let mask = if object.opacity > 0.0 { 0xFF } else { 0x00 };
+
+            let instance = vk::AccelerationStructureInstanceKHR {
+                transform: vk_transform(&object.transform.affine()),
+                instance_custom_index_and_mask: vk::Packed24_8::new(i as u32, mask),
+                instance_shader_binding_table_record_offset_and_flags: vk::Packed24_8::new(
+                    object.hit_offset,
+                    flags.as_raw() as u8,
+                ),
+                acceleration_structure_reference: object.reference,
+            };
+            let data = InstanceData {
+                opacity: object.opacity,
+                material_offset,
+            };
+            material_offset += object.materials.len() as u32;
+
+            (instance, data, object.materials.clone())
+        })
+        .collect()
 }
 
 pub fn update_tlas(
@@ -212,151 +462,131 @@ pub fn update_tlas(
     mesh_components: Query<(Entity, &Mesh3d)>,
     gltf_components: Query<(Entity, &GltfModelHandle)>,
     material_components: Query<&MeshMaterial3d<StandardMaterial>>,
+    opacities: Query<&InstanceOpacity>,
+    previous_transforms: Query<&PreviousGlobalTransform>,
     sphere_blas: Res<SphereBLAS>,
     spheres: Query<(Entity, &crate::sphere::Sphere)>,
-    transforms: Query<&GlobalTransform>,
+    transforms: Query<(&GlobalTransform, &MainEntity)>,
+    cameras: Query<&GlobalTransform, With<Camera>>,
+    render_config: Res<RenderConfig>,
+    mut kept: Local<HashSet<Entity>>,
 ) {
-    tlas.mesh_to_hit_offset.clear();
-    // Reserve the first offset for the sphere hit group
-    let mut hit_group_offset_gen = 1;
-
-    let mut objects: Vec<(
-        Entity,
-        u32,
-        GlobalTransform,
-        vk::AccelerationStructureReferenceKHR,
-        &Option<Vec<RTXMaterial>>,
-    )> = Vec::new();
+    let object_materials = |e: Entity, gltf_materials: &Option<Vec<RTXMaterial>>| {
+        if let Ok(material_handle) = material_components.get(e) {
+            vec![materials.get(material_handle).cloned().unwrap_or_default()]
+        } else {
+            if let Some(gltf_materials) = gltf_materials {
+                gltf_materials.clone()
+            } else {
+                log::warn!("No material found for entity {:?}", e);
+                vec![RTXMaterial::default()]
+            }
+        }
+    };
+    let object_opacity = |e: Entity| {
+        opacities
+            .get(e)
+            .map_or(1.0, |opacity| opacity.0.clamp(0.0, 1.0))
+    };
+
+    let mut objects: Vec<TLASObject> = Vec::new();
     objects.extend(mesh_components.iter().filter_map(|(e, mesh_handle)| {
         let blas = meshes.get(mesh_handle)?;
-        let transform = transforms.get(e).unwrap();
-        let hit_offset =
-            if let Some(hit_offset) = tlas.mesh_to_hit_offset.get(&mesh_handle.id().untyped()) {
-                *hit_offset
-            } else {
-                let old_val = hit_group_offset_gen;
-                hit_group_offset_gen += 1;
-                tlas.mesh_to_hit_offset
-                    .insert(mesh_handle.id().untyped(), old_val);
-                old_val
-            };
-
-        Some((
-            e,
-            hit_offset,
-            transform.clone(),
-            blas.acceleration_structure.get_reference(),
-            &blas.gltf_materials,
-        ))
+        let (transform, main_entity) = transforms.get(e).unwrap();
+        Some(TLASObject {
+            entity: e,
+            main_entity: main_entity.0,
+            mesh: Some((mesh_handle.id().untyped(), blas.geometry_count())),
+            hit_offset: 0,
+            transform: transform.clone(),
+            reference: blas.acceleration_structure.get_reference(),
+            materials: object_materials(e, &blas.gltf_materials),
+            opacity: object_opacity(e),
+            opaque: blas.opaque,
+        })
     }));
 
     objects.extend(gltf_components.iter().filter_map(|(e, gltf_handle)| {
         let blas = gltf_meshes.get(gltf_handle)?;
-        let transform = transforms.get(e).unwrap();
-        let hit_offset =
-            if let Some(hit_offset) = tlas.mesh_to_hit_offset.get(&gltf_handle.id().untyped()) {
-                *hit_offset
-            } else {
-                let old_val = hit_group_offset_gen;
-                hit_group_offset_gen += 1;
-                tlas.mesh_to_hit_offset
-                    .insert(gltf_handle.id().untyped(), old_val);
-                old_val
-            };
-
-        Some((
-            e,
-            hit_offset,
-            transform.clone(),
-            blas.acceleration_structure.get_reference(),
-            &blas.gltf_materials,
-        ))
+        let (transform, main_entity) = transforms.get(e).unwrap();
+        Some(TLASObject {
+            entity: e,
+            main_entity: main_entity.0,
+            mesh: Some((gltf_handle.id().untyped(), blas.geometry_count())),
+            hit_offset: 0,
+            transform: transform.clone(),
+            reference: blas.acceleration_structure.get_reference(),
+            materials: object_materials(e, &blas.gltf_materials),
+            opacity: object_opacity(e),
+            opaque: blas.opaque,
+        })
     }));
 
     for (sphere_e, _) in spheres.iter() {
-        let transform = transforms.get(sphere_e).unwrap();
-        objects.push((
-            sphere_e,
-            0,
-            transform.clone(),
-            sphere_blas.acceleration_structure.get_reference(),
-            &None,
-        ));
+        let (transform, main_entity) = transforms.get(sphere_e).unwrap();
+        objects.push(TLASObject {
+            entity: sphere_e,
+            main_entity: main_entity.0,
+            mesh: None,
+            hit_offset: 0,
+            transform: transform.clone(),
+            reference: sphere_blas.acceleration_structure.get_reference(),
+            materials: object_materials(sphere_e, &None),
+            opacity: object_opacity(sphere_e),
+            opaque: true,
+        });
     }
 
-    let mut material_offset = 0;
-    let instances: Vec<(vk::AccelerationStructureInstanceKHR, Vec<RTXMaterial>)> = objects
+    // a runaway spawn should degrade the image, not exhaust the memory or the device,
+    // and the instance index has to fit the 24 bits of the custom index
+    let max_instance_count = render_device
+        .acceleration_structure_properties()
+        .max_instance_count
+        .min(1 << 24);
+    let max_instances = (render_config.max_instances as u64).min(max_instance_count) as usize;
+    if objects.len() > max_instances && kept.is_empty() {
+        log::warn!(
+            "{} instances exceed the limit of {}, leaving out the most distant ones",
+            objects.len(),
+            max_instances
+        );
+    }
+    let eye = cameras
         .iter()
-        .map(|(e, hit_offset, transform, reference, mat_bundle)| {
-            let columns = transform.affine().to_cols_array_2d();
-            let transform = vk::TransformMatrixKHR {
-                matrix: [
-                    columns[0][0],
-                    columns[1][0],
-                    columns[2][0],
-                    columns[3][0],
-                    columns[0][1],
-                    columns[1][1],
-                    columns[2][1],
-                    columns[3][1],
-                    columns[0][2],
-                    columns[1][2],
-                    columns[2][2],
-                    columns[3][2],
-                ],
-            };
+        .next()
+        .map_or(Vec3::ZERO, |camera| camera.translation());
+    limit_instances(&mut objects, eye, max_instances, &mut kept);
 
-            let instance = vk::AccelerationStructureInstanceKHR {
-                transform,
-                instance_custom_index_and_mask: vk::Packed24_8::new(material_offset, 0xFF),
-                instance_shader_binding_table_record_offset_and_flags: vk::Packed24_8::new(
-                    *hit_offset,
-                    0b1,
-                ),
-                acceleration_structure_reference: *reference,
-            };
+    // only the meshes that are left get hit records
+    let tlas = &mut *tlas;
+    tlas.hit_record_count = assign_hit_offsets(&mut objects, &mut tlas.mesh_to_hit_offset);
 
-            let material_slice = if let Ok(material_handle) = material_components.get(*e) {
-                vec![materials.get(material_handle).cloned().unwrap_or_default()]
-            } else {
-                if let Some(gltf_materials) = mat_bundle {
-                    gltf_materials.clone()
-                } else {
-                    log::warn!("No material found for entity {:?}", e);
-                    vec![RTXMaterial::default()]
-                }
-            };
-            material_offset += material_slice.len() as u32;
-
-            (instance, material_slice)
-        })
-        .collect();
+    let instances = build_instances(&objects);
 
     if instances.is_empty() {
         return;
     }
 
+    tlas.has_faded_instances = instances.iter().any(|(_, data, _)| data.opacity < 1.0);
+    tlas.has_non_opaque_geometry = objects.iter().any(|object| !object.opaque);
+
     tlas.update(&render_device, &instances);
+    tlas.motion = objects
+        .iter()
+        .map(|object| {
+            let previous = previous_transforms
+                .get(object.entity)
+                .map_or(object.transform, |previous| previous.0);
+            (previous.affine(), object.transform.affine())
+        })
+        .collect();
+    tlas.instances = instances.into_iter().map(|(i, _, _)| i).collect();
 }
 
 fn cleanup_tlas(world: &mut World) {
     let tlas = world.remove_resource::<TLAS>().unwrap();
     let render_device = world.get_resource::<RenderDevice>().unwrap();
-    render_device
-        .destroyer
-        .destroy_acceleration_structure(tlas.acceleration_structure.handle);
-    render_device
-        .destroyer
-        .destroy_buffer(tlas.acceleration_structure.buffer.handle);
-    render_device
-        .destroyer
-        .destroy_buffer(tlas.instance_buffer.handle);
-    render_device
-        .destroyer
-        .destroy_buffer(tlas.scratch_buffer.handle);
-    render_device
-        .destroyer
-        .destroy_buffer(tlas.material_buffer.handle);
+    tlas.destroy(render_device);
 }
 
 pub struct TLASBuilderPlugin;
@@ -367,6 +597,180 @@ impl Plugin for TLASBuilderPlugin {
 
         render_app.init_resource::<TLAS>();
         render_app.add_systems(Render, update_tlas.in_set(RenderSet::Prepare));
-        render_app.add_systems(TeardownSchedule, cleanup_tlas);
+        app.add_render_teardown(cleanup_tlas);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        extract::{run_extract, MainEntity},
+        gltf_mesh::extract_gltfs,
+        sphere::{extract_spheres, Sphere},
+    };
+
+    #[test]
+    fn instances_spawned_with_only_a_transform() {
+        let mut app = App::new();
+        app.add_plugins(bevy::transform::TransformPlugin);
+
+        let transform = Transform::from_xyz(1.0, 2.0, 3.0)
+            .with_rotation(Quat::from_rotation_y(0.5))
+            .with_scale(Vec3::splat(2.0));
+        app.world_mut().spawn((
+            Sphere,
+            MeshMaterial3d::<StandardMaterial>(Handle::default()),
+            transform,
+        ));
+        app.world_mut()
+            .spawn((GltfModelHandle(Handle::default()), transform));
+        // propagates the required GlobalTransform
+        app.update();
+
+        let mut render_world = run_extract(app.world_mut(), (extract_spheres, extract_gltfs));
+
+        let expected = transform.compute_affine();
+        let mut instances = render_world.query_filtered::<
+            (&GlobalTransform, &PreviousGlobalTransform),
+            Or<(With<Sphere>, With<GltfModelHandle>)>,
+        >();
+        assert_eq!(instances.iter(&render_world).count(), 2);
+        for (transform, previous) in instances.iter(&render_world) {
+            assert!(transform.affine().abs_diff_eq(expected, 1e-5));
+            assert!(previous.0.affine().abs_diff_eq(expected, 1e-5));
+
+            // the translation column of the TLAS instance
+            let matrix = vk_transform(&transform.affine()).matrix;
+            assert!(Vec3::new(matrix[3], matrix[7], matrix[11])
+                .abs_diff_eq(Vec3::new(1.0, 2.0, 3.0), 1e-5));
+        }
+    }
+
+    #[test]
+    fn instance_data_follows_the_limited_instances() {
+        let objects_at = |distances: &[f32]| -> Vec<TLASObject> {
+            distances
+                .iter()
+                .enumerate()
+                .map(|(i, &distance)| TLASObject {
+                    entity: Entity::from_raw(i as u32),
+                    main_entity: Entity::from_raw(i as u32),
+                    mesh: None,
+                    hit_offset: 0,
+                    transform: GlobalTransform::from_translation(Vec3::X * distance),
+                    reference: vk::AccelerationStructureReferenceKHR { device_handle: 0 },
+                    // a varying number of materials, tagged with the distance
+                    materials: vec![
+                        RTXMaterial {
+                            base_color_factor: [distance; 4],
+                            ..default()
+                        };
+                        i % 3 + 1
+                    ],
+                    opacity: distance / 10.0,
+                    opaque: true,
+                })
+                .collect()
+        };
+
+        let mut objects = objects_at(&[5.0, 1.0, 9.0, 3.0, 7.0, 2.0]);
+        limit_instances(&mut objects, Vec3::ZERO, 4, &mut HashSet::new());
+        let instances = build_instances(&objects);
+
+        let materials: Vec<RTXMaterial> = instances
+            .iter()
+            .flat_map(|(_, _, materials)| materials.iter().cloned())
+            .collect();
+        assert_eq!(instances.len(), 4);
+        for (i, (instance, data, _)) in instances.iter().enumerate() {
+            // the shaders index the instance data buffer with the custom index
+            assert_eq!(instance.instance_custom_index_and_mask.low_24(), i as u32);
+            let distance = instance.transform.matrix[3];
+            assert!([1.0, 2.0, 3.0, 5.0].contains(&distance));
+            assert_eq!(data.opacity, distance / 10.0);
+            assert_eq!(
+                materials[data.material_offset as usize].base_color_factor,
+                [distance; 4]
+            );
+        }
+    }
+
+    #[test]
+    fn limited_instances_are_stable() {
+        let mut app = App::new();
+        app.add_plugins(bevy::transform::TransformPlugin);
+        for x in 1..=6 {
+            app.world_mut().spawn((
+                Sphere,
+                MeshMaterial3d::<StandardMaterial>(Handle::default()),
+                Transform::from_xyz(x as f32, 0.0, 0.0),
+            ));
+        }
+        app.update();
+
+        // the render world is extracted anew every frame, like the render app does
+        let mut kept = HashSet::new();
+        let mut limit = |main_world: &mut World, eye: f32| -> Vec<f32> {
+            let mut render_world = run_extract(main_world, extract_spheres);
+            let mut objects: Vec<TLASObject> = render_world
+                .query::<(Entity, &MainEntity, &GlobalTransform)>()
+                .iter(&render_world)
+                .map(|(entity, main_entity, transform)| TLASObject {
+                    entity,
+                    main_entity: main_entity.0,
+                    mesh: None,
+                    hit_offset: 0,
+                    transform: *transform,
+                    reference: vk::AccelerationStructureReferenceKHR { device_handle: 0 },
+                    materials: vec![RTXMaterial::default()],
+                    opacity: 1.0,
+                    opaque: true,
+                })
+                .collect();
+            limit_instances(&mut objects, Vec3::X * eye, 3, &mut kept);
+            let mut kept_x: Vec<f32> = objects
+                .iter()
+                .map(|object| object.transform.translation().x)
+                .collect();
+            kept_x.sort_by(f32::total_cmp);
+            kept_x
+        };
+
+        assert_eq!(limit(app.world_mut(), 0.0), [1.0, 2.0, 3.0]);
+        // the sphere at 4 is now slightly closer than the one at 1, but not by enough
+        assert_eq!(limit(app.world_mut(), 2.55), [1.0, 2.0, 3.0]);
+        assert_eq!(limit(app.world_mut(), 3.5), [2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn left_out_meshes_have_no_hit_records() {
+        let mesh = |i: u128| Some((Handle::<Mesh>::weak_from_u128(i).id().untyped(), 2));
+        let mut objects: Vec<TLASObject> =
+            [(mesh(1), 1.0), (None, 2.0), (mesh(2), 3.0), (mesh(1), 4.0)]
+                .into_iter()
+                .enumerate()
+                .map(|(i, (mesh, distance))| TLASObject {
+                    entity: Entity::from_raw(i as u32),
+                    main_entity: Entity::from_raw(i as u32),
+                    mesh,
+                    hit_offset: 0,
+                    transform: GlobalTransform::from_translation(Vec3::X * distance),
+                    reference: vk::AccelerationStructureReferenceKHR { device_handle: 0 },
+                    materials: vec![RTXMaterial::default()],
+                    opacity: 1.0,
+                    opaque: true,
+                })
+                .collect();
+
+        limit_instances(&mut objects, Vec3::ZERO, 2, &mut HashSet::new());
+        let mut mesh_to_hit_offset = HashMap::new();
+        let hit_record_count = assign_hit_offsets(&mut objects, &mut mesh_to_hit_offset);
+
+        // the sphere record and the two geometries of the only mesh left
+        assert_eq!(hit_record_count, 3);
+        assert_eq!(mesh_to_hit_offset.len(), 1);
+        assert_eq!(objects[0].hit_offset, 1);
+        assert_eq!(objects[1].hit_offset, 0);
     }
 }
@@ -0,0 +1,183 @@
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+
+use crate::{
+    ray_render_plugin::{AccumulationStatus, RenderConfig},
+    screenshot::Screenshot,
+    vulkan_asset::VulkanAssetProgress,
+};
+
+/// Orbits the camera around `target`, accumulating `samples_per_step` frames
+/// at each of the `steps` stops and saving every stop to `output_dir` as
+/// `frame_0000.png`, `frame_0001.png`, ...
+///
+/// Inserting the resource starts the turntable, it is removed when done. Nothing
+/// else should move the camera in the meantime (like the `DebugCamera`). The
+/// accumulation settings of the [`RenderConfig`] are restored once it is done or
+/// removed.
+#[derive(Resource, Clone, Debug)]
+pub struct Turntable {
+    pub target: Vec3,
+    pub radius: f32,
+    /// Height of the camera above the target.
+    pub height: f32,
+    pub steps: u32,
+    /// Accumulated frames per stop, see [`RenderConfig::max_samples`].
+    pub samples_per_step: u32,
+    pub output_dir: PathBuf,
+}
+
+impl Turntable {
+    pub fn new(target: Vec3, radius: f32, steps: u32, samples_per_step: u32) -> Self {
+        Self {
+            target,
+            radius,
+            height: 0.0,
+            steps,
+            samples_per_step,
+            output_dir: PathBuf::from("turntable"),
+        }
+    }
+
+    pub fn camera_transform(&self, step: u32) -> Transform {
+        let angle = std::f32::consts::TAU * step as f32 / self.steps.max(1) as f32;
+        let offset = Vec3::new(
+            angle.cos() * self.radius,
+            self.height,
+            angle.sin() * self.radius,
+        );
+        Transform::from_translation(self.target + offset).looking_at(self.target, Vec3::Y)
+    }
+}
+
+/// Sent when the last frame of a [`Turntable`] has been requested.
+#[derive(Event, Clone, Debug)]
+pub struct TurntableFinished;
+
+#[derive(Default, Clone, Copy, Debug)]
+enum TurntablePhase {
+    #[default]
+    Move,
+    /// Waiting for the render app to pick up the new camera.
+    Reset,
+    Accumulate,
+}
+
+#[derive(Resource, Default)]
+struct TurntableState {
+    step: u32,
+    phase: TurntablePhase,
+    view: Mat4,
+    /// `accumulate` and `max_samples` of the config before the turntable started.
+    previous_accumulation: (bool, Option<u32>),
+}
+
+impl TurntableState {
+    fn restore(&self, render_config: &mut RenderConfig) {
+        (render_config.accumulate, render_config.max_samples) = self.previous_accumulation;
+    }
+}
+
+pub struct TurntablePlugin;
+
+impl Plugin for TurntablePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<TurntableFinished>();
+        app.add_systems(Update, drive_turntable);
+    }
+}
+
+fn drive_turntable(
+    mut commands: Commands,
+    turntable: Option<Res<Turntable>>,
+    state: Option<ResMut<TurntableState>>,
+    accumulation: Res<AccumulationStatus>,
+    asset_progress: Option<Res<VulkanAssetProgress>>,
+    mut render_config: ResMut<RenderConfig>,
+    mut cameras: Query<&mut Transform, With<Camera3d>>,
+    mut screenshots: EventWriter<Screenshot>,
+    mut finished: EventWriter<TurntableFinished>,
+) {
+    let Some(turntable) = turntable else {
+        if let Some(state) = state {
+            state.restore(&mut render_config);
+            commands.remove_resource::<TurntableState>();
+        }
+        return;
+    };
+
+    let Some(mut state) = state else {
+        if let Err(e) = std::fs::create_dir_all(&turntable.output_dir) {
+            log::error!(
+                "Could not create turntable output {}: {}",
+                turntable.output_dir.display(),
+                e
+            );
+            commands.remove_resource::<Turntable>();
+            return;
+        }
+        commands.insert_resource(TurntableState {
+            previous_accumulation: (render_config.accumulate, render_config.max_samples),
+            ..default()
+        });
+        return;
+    };
+
+    // frames traced while assets were still uploading would end up in the image
+    let uploading = asset_progress.is_some_and(|progress| progress.pending() > 0);
+    let status = accumulation.get();
+    let at_stop = status.view.abs_diff_eq(state.view, 1e-4);
+
+    match state.phase {
+        TurntablePhase::Move => {
+            if uploading {
+                return;
+            }
+            let transform = turntable.camera_transform(state.step);
+            for mut camera in cameras.iter_mut() {
+                *camera = transform;
+            }
+            state.view = transform.compute_matrix();
+            render_config.accumulate = false;
+            render_config.max_samples = Some(turntable.samples_per_step);
+            state.phase = TurntablePhase::Reset;
+        }
+        TurntablePhase::Reset => {
+            if at_stop {
+                render_config.accumulate = true;
+                state.phase = TurntablePhase::Accumulate;
+            }
+        }
+        TurntablePhase::Accumulate => {
+            if uploading {
+                state.phase = TurntablePhase::Move;
+                return;
+            }
+            if !at_stop || status.frames < turntable.samples_per_step {
+                return;
+            }
+
+            // the image no longer changes, so the next rendered frame is the one captured.
+            screenshots.send(Screenshot::png(
+                turntable
+                    .output_dir
+                    .join(format!("frame_{:04}.png", state.step)),
+            ));
+
+            state.step += 1;
+            state.phase = TurntablePhase::Move;
+            if state.step >= turntable.steps {
+                log::info!(
+                    "Turntable finished, {} frames written to {}",
+                    turntable.steps,
+                    turntable.output_dir.display()
+                );
+                state.restore(&mut render_config);
+                commands.remove_resource::<Turntable>();
+                commands.remove_resource::<TurntableState>();
+                finished.send(TurntableFinished);
+            }
+        }
+    }
+}
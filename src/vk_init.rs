@@ -56,8 +56,12 @@ pub fn layout_transition2<'a>(
         })
 }
 
-pub fn buffer_image_copy(width: u32, height: u32) -> vk::BufferImageCopy {
+/// `row_length` is the distance between rows in the buffer in texels,
+/// 0 means the rows are tightly packed.
+pub fn buffer_image_copy(width: u32, height: u32, row_length: u32) -> vk::BufferImageCopy {
     vk::BufferImageCopy::default()
+        .buffer_row_length(row_length)
+        .buffer_image_height(height)
         .image_extent(vk::Extent3D {
             width,
             height,
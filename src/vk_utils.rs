@@ -23,31 +23,61 @@ pub fn transition_image_layout(
     }
 }
 
-pub fn get_raytracing_properties(
-    device: &RenderDevice,
-) -> vk::PhysicalDeviceRayTracingPipelinePropertiesKHR {
-    let mut raytracing_properties = vk::PhysicalDeviceRayTracingPipelinePropertiesKHR::default();
-    let mut properties2 =
-        vk::PhysicalDeviceProperties2KHR::default().push_next(&mut raytracing_properties);
+/// Makes the acceleration structures built before visible to the traces after it.
+pub fn build_to_trace_barrier(device: &RenderDevice, cmd_buffer: vk::CommandBuffer) {
+    let memory_barrier = vk::MemoryBarrier2::default()
+        .src_stage_mask(vk::PipelineStageFlags2::ACCELERATION_STRUCTURE_BUILD_KHR)
+        .src_access_mask(vk::AccessFlags2::ACCELERATION_STRUCTURE_WRITE_KHR)
+        .dst_stage_mask(vk::PipelineStageFlags2::RAY_TRACING_SHADER_KHR)
+        .dst_access_mask(vk::AccessFlags2::ACCELERATION_STRUCTURE_READ_KHR);
+    let barrier_info =
+        vk::DependencyInfo::default().memory_barriers(std::slice::from_ref(&memory_barrier));
     unsafe {
         device
-            .instance
-            .get_physical_device_properties2(device.physical_device, &mut properties2)
+            .ext_sync2
+            .cmd_pipeline_barrier2(cmd_buffer, &barrier_info);
     }
-    raytracing_properties
 }
 
-pub fn get_acceleration_structure_properties(
+/// Orders two traces of the same frame, the second one accumulates onto the render
+/// target (and focus data) the first one wrote.
+pub fn trace_to_trace_barrier(device: &RenderDevice, cmd_buffer: vk::CommandBuffer) {
+    let memory_barrier = vk::MemoryBarrier2::default()
+        .src_stage_mask(vk::PipelineStageFlags2::RAY_TRACING_SHADER_KHR)
+        .src_access_mask(vk::AccessFlags2::SHADER_STORAGE_WRITE)
+        .dst_stage_mask(vk::PipelineStageFlags2::RAY_TRACING_SHADER_KHR)
+        .dst_access_mask(
+            vk::AccessFlags2::SHADER_STORAGE_READ | vk::AccessFlags2::SHADER_STORAGE_WRITE,
+        );
+    let barrier_info =
+        vk::DependencyInfo::default().memory_barriers(std::slice::from_ref(&memory_barrier));
+    unsafe {
+        device
+            .ext_sync2
+            .cmd_pipeline_barrier2(cmd_buffer, &barrier_info);
+    }
+}
+
+/// Makes the writes of the trace to `image` (in `GENERAL`) visible to a copy.
+pub fn trace_to_copy_barrier(
     device: &RenderDevice,
-) -> vk::PhysicalDeviceAccelerationStructurePropertiesKHR {
-    let mut acceleration_structure_properties =
-        vk::PhysicalDeviceAccelerationStructurePropertiesKHR::default();
-    let mut properties2 = vk::PhysicalDeviceProperties2KHR::default()
-        .push_next(&mut acceleration_structure_properties);
+    cmd_buffer: vk::CommandBuffer,
+    image: vk::Image,
+) {
+    let image_barrier = crate::vk_init::layout_transition2(
+        image,
+        vk::ImageLayout::GENERAL,
+        vk::ImageLayout::GENERAL,
+    )
+    .src_stage_mask(vk::PipelineStageFlags2::RAY_TRACING_SHADER_KHR)
+    .src_access_mask(vk::AccessFlags2::SHADER_STORAGE_WRITE)
+    .dst_stage_mask(vk::PipelineStageFlags2::COPY)
+    .dst_access_mask(vk::AccessFlags2::TRANSFER_READ);
+    let barrier_info =
+        vk::DependencyInfo::default().image_memory_barriers(std::slice::from_ref(&image_barrier));
     unsafe {
         device
-            .instance
-            .get_physical_device_properties2(device.physical_device, &mut properties2)
+            .ext_sync2
+            .cmd_pipeline_barrier2(cmd_buffer, &barrier_info);
     }
-    acceleration_structure_properties
 }
@@ -1,3 +1,8 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
 use bevy::{
     app::App,
     asset::{Asset, AssetEvent, AssetId, Assets, Handle},
@@ -15,7 +20,7 @@ use crossbeam::channel::{Receiver, Sender};
 
 use crate::{
     extract::Extract,
-    ray_render_plugin::{Render, RenderSet, TeardownSchedule},
+    ray_render_plugin::{Render, RenderAppExt, RenderSet},
     render_device::RenderDevice,
 };
 
@@ -29,6 +34,15 @@ pub trait VulkanAsset: Asset + Clone + Send + Sync + 'static {
         param: &mut SystemParamItem<Self::ExtractParam>,
     ) -> Option<Self::ExtractedAsset>;
 
+    /// Used instead of [`VulkanAsset::extract_asset`] when the asset changed after it
+    /// was added, assets that keep changing can be prepared differently.
+    fn extract_modified_asset(
+        &self,
+        param: &mut SystemParamItem<Self::ExtractParam>,
+    ) -> Option<Self::ExtractedAsset> {
+        self.extract_asset(param)
+    }
+
     fn prepare_asset(
         asset: Self::ExtractedAsset,
         render_device: &RenderDevice,
@@ -36,14 +50,26 @@ pub trait VulkanAsset: Asset + Clone + Send + Sync + 'static {
     fn destroy_asset(render_device: &RenderDevice, prepared_asset: &Self::PreparedAsset);
 }
 
+/// Number of assets (of any type) that are waiting for or being prepared
+/// on a worker thread.
+#[derive(Resource, Clone, Default)]
+pub struct VulkanAssetProgress(Arc<AtomicUsize>);
+
+impl VulkanAssetProgress {
+    pub fn pending(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
 #[derive(Resource)]
 pub struct VulkanAssetComms<A: VulkanAsset> {
     send_work: Sender<(AssetId<A>, A::ExtractedAsset)>,
     recv_result: Receiver<(AssetId<A>, A::PreparedAsset)>,
+    progress: VulkanAssetProgress,
 }
 
 impl<A: VulkanAsset> VulkanAssetComms<A> {
-    fn new(render_device: RenderDevice) -> Self {
+    fn new(render_device: RenderDevice, progress: VulkanAssetProgress) -> Self {
         let (send_work, recv_work) =
             crossbeam::channel::unbounded::<(AssetId<A>, A::ExtractedAsset)>();
         let (send_result, recv_result) = crossbeam::channel::unbounded();
@@ -51,11 +77,14 @@ impl<A: VulkanAsset> VulkanAssetComms<A> {
         let ret = Self {
             send_work,
             recv_result,
+            progress: progress.clone(),
         };
 
         std::thread::spawn(move || {
             while let Ok((id, asset)) = recv_work.recv() {
-                if let Err(_) = send_result.send((id, A::prepare_asset(asset, &render_device))) {
+                let prepared = A::prepare_asset(asset, &render_device);
+                progress.0.fetch_sub(1, Ordering::Relaxed);
+                if let Err(_) = send_result.send((id, prepared)) {
                     break;
                 }
             }
@@ -63,6 +92,11 @@ impl<A: VulkanAsset> VulkanAssetComms<A> {
 
         ret
     }
+
+    fn send_work(&self, id: AssetId<A>, extracted: A::ExtractedAsset) {
+        self.progress.0.fetch_add(1, Ordering::Relaxed);
+        self.send_work.send((id, extracted)).unwrap();
+    }
 }
 
 pub enum VulkanAssetLoadingState<A: VulkanAsset> {
@@ -109,7 +143,7 @@ fn extract_vulkan_asset<A: VulkanAsset>(
                             .insert(*id, VulkanAssetLoadingState::Loading)
                             .is_none()
                         {
-                            comms.send_work.send((*id, extracted)).unwrap();
+                            comms.send_work(*id, extracted);
                         }
                     }
                 } else {
@@ -122,8 +156,8 @@ fn extract_vulkan_asset<A: VulkanAsset>(
                     id
                 );
                 if let Some(asset) = assets.get(*id) {
-                    if let Some(extracted) = asset.extract_asset(&mut param) {
-                        comms.send_work.send((*id, extracted)).unwrap();
+                    if let Some(extracted) = asset.extract_modified_asset(&mut param) {
+                        comms.send_work(*id, extracted);
                     }
                 } else {
                     log::warn!("VulkanAsset could not find asset with id: {:?}", id);
@@ -146,7 +180,7 @@ fn extract_vulkan_asset<A: VulkanAsset>(
                             .insert(*id, VulkanAssetLoadingState::Loading)
                             .is_none()
                         {
-                            comms.send_work.send((*id, extracted)).unwrap();
+                            comms.send_work(*id, extracted);
                         }
                     }
                 } else {
@@ -206,10 +240,16 @@ impl VulkanAssetExt for App {
             .get_resource::<RenderDevice>()
             .unwrap()
             .clone();
-        render_app.insert_resource(VulkanAssetComms::<A>::new(render_device));
+        let progress = render_app
+            .world_mut()
+            .get_resource_or_insert_with(VulkanAssetProgress::default)
+            .clone();
+        render_app.insert_resource(VulkanAssetComms::<A>::new(render_device, progress.clone()));
         render_app.init_resource::<VulkanAssets<A>>();
         render_app.add_systems(ExtractSchedule, extract_vulkan_asset::<A>);
         render_app.add_systems(Render, poll_for_asset::<A>.in_set(RenderSet::Prepare));
-        render_app.add_systems(TeardownSchedule, on_shutdown::<A>);
+        // the main app can wait for uploads to finish
+        self.insert_resource(progress);
+        self.add_render_teardown(on_shutdown::<A>);
     }
 }
@@ -4,15 +4,17 @@ use bevy::{
 };
 
 use crate::{
-    blas::{build_blas_from_buffers, GeometryDescr, Vertex, BLAS},
-    extract::Extract,
+    blas::{build_blas_from_buffers, BlasBuildOptions, GeometryDescr, Vertex, BLAS},
+    extract::{Extract, MainEntity},
+    motion_blur::PreviousGlobalTransform,
     render_buffer::BufferProvider,
+    tlas_builder::InstanceOpacity,
     vulkan_asset::{VulkanAsset, VulkanAssetExt},
 };
 use ash::vk;
 
 impl VulkanAsset for Mesh {
-    type ExtractedAsset = Mesh;
+    type ExtractedAsset = (Mesh, BlasBuildOptions);
     type ExtractParam = ();
     type PreparedAsset = BLAS;
 
@@ -20,11 +22,19 @@ impl VulkanAsset for Mesh {
         &self,
         _param: &mut bevy::ecs::system::SystemParamItem<Self::ExtractParam>,
     ) -> Option<Self::ExtractedAsset> {
-        Some(self.clone())
+        Some((self.clone(), BlasBuildOptions::default()))
+    }
+
+    /// A mesh that was changed after it was added is likely to change again.
+    fn extract_modified_asset(
+        &self,
+        _param: &mut bevy::ecs::system::SystemParamItem<Self::ExtractParam>,
+    ) -> Option<Self::ExtractedAsset> {
+        Some((self.clone(), BlasBuildOptions::dynamic()))
     }
 
     fn prepare_asset(
-        asset: Self::ExtractedAsset,
+        (asset, build_options): Self::ExtractedAsset,
         render_device: &crate::render_device::RenderDevice,
     ) -> Self::PreparedAsset {
         let vertex_count = asset.count_vertices();
@@ -68,7 +78,9 @@ impl VulkanAsset for Mesh {
                 vertex_count,
                 first_index: 0,
                 index_count,
+                opaque: true,
             }],
+            build_options,
         )
     }
 
@@ -86,15 +98,26 @@ fn extract_meshes(
     mut commands: Commands,
     meshes: Extract<
         Query<(
+            Entity,
             &Mesh3d,
             &MeshMaterial3d<StandardMaterial>,
             &Transform,
             &GlobalTransform,
+            Option<&InstanceOpacity>,
+            Option<&PreviousGlobalTransform>,
         )>,
     >,
 ) {
-    for (mesh, mat, t, gt) in meshes.iter() {
-        commands.spawn((mesh.clone(), mat.clone(), t.clone(), gt.clone()));
+    for (e, mesh, mat, t, gt, opacity, previous) in meshes.iter() {
+        commands.spawn((
+            MainEntity(e),
+            mesh.clone(),
+            mat.clone(),
+            t.clone(),
+            gt.clone(),
+            opacity.copied().unwrap_or_default(),
+            previous.copied().unwrap_or(PreviousGlobalTransform(*gt)),
+        ));
     }
 }
 